@@ -0,0 +1,150 @@
+//! Measures the time and heap allocations per frame of running every model in `models/`,
+//! comparing the previous approach (a fresh input tensor copied for each model, and
+//! cloned names in a fresh result vector) with the [Runner], with and without fusing.
+//!
+//! The allocations which remain in the [Runner] are made by tract while executing each
+//! model, such as for the outputs of each operation.
+//!
+//! Run with `cargo bench --bench runner`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::time::{Duration, Instant};
+
+use oww_rust_core::*;
+use tract_onnx::prelude::*;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const FRAMES: usize = 200;
+const WARMUP: usize = 5;
+
+fn model_paths() -> Vec<String> {
+    let mut out: Vec<String> = std::fs::read_dir("models")
+        .expect("failed reading models directory")
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "onnx"))
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    out.sort();
+    out
+}
+
+fn report(name: &str, elapsed: Duration, allocations: usize) {
+    println!(
+        "{:<16} {:>8.3} ms/frame {:>10.1} allocations/frame",
+        name,
+        elapsed.as_secs_f64() * 1000. / FRAMES as f64,
+        allocations as f64 / FRAMES as f64
+    );
+}
+
+fn bench_previous(paths: &[String]) {
+    let models: Vec<(String, TypedRunnableModel<TypedModel>)> = paths
+        .iter()
+        .map(|p| {
+            let model = tract_onnx::onnx()
+                .model_for_path(p)
+                .unwrap()
+                .into_optimized()
+                .unwrap()
+                .into_runnable()
+                .unwrap();
+            (p.clone(), model)
+        })
+        .collect();
+    let embeddings = vec![Embedding::default(); NUM_EMBEDDINGS];
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        let input = Tensor::from_shape(
+            &[1, NUM_EMBEDDINGS, 96],
+            embeddings
+                .iter()
+                .flat_map(|e| e.iter())
+                .copied()
+                .collect::<Vec<_>>()
+                .as_slice(),
+        )
+        .unwrap();
+        let results: Vec<(String, f32)> = models
+            .iter()
+            .map(|(name, m)| {
+                let out = m.run(tvec!(TValue::from(input.clone()))).unwrap();
+                (name.clone(), out[0].as_slice::<f32>().unwrap()[0])
+            })
+            .collect();
+        std::hint::black_box(results);
+    }
+    report(
+        "previous",
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed) - before,
+    );
+}
+
+fn bench_runner(paths: &[String], threads: usize, fused: bool) {
+    let (send, recv) = sync_channel(1);
+    let config = RunnerConfig {
+        threads: Some(threads),
+        fused,
+        ..RunnerConfig::default()
+    };
+    let mut runner = Runner::start_with_config(recv, &config).unwrap();
+    for p in paths {
+        runner.add_model(NamedModel::new(p.as_str(), p.as_str(), 1.).unwrap());
+    }
+    let results = runner.take_receiver().unwrap();
+
+    // Fill the window of embeddings, then warm up the recycled buffers.
+    let feeder = std::thread::spawn(move || {
+        for _ in 0..NUM_EMBEDDINGS - 1 + WARMUP + FRAMES {
+            send.send(Embedding::default()).unwrap();
+        }
+    });
+    for _ in 0..WARMUP {
+        runner.recycle(results.recv().unwrap());
+    }
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        let r = results.recv().unwrap();
+        std::hint::black_box(&r);
+        runner.recycle(r);
+    }
+    report(
+        &format!("runner x{}{}", threads, if fused { " fused" } else { "" }),
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed) - before,
+    );
+    feeder.join().unwrap();
+}
+
+fn main() {
+    let paths = model_paths();
+    println!("{} models, {} frames", paths.len(), FRAMES);
+    bench_previous(&paths);
+    bench_runner(&paths, 1, false);
+    bench_runner(&paths, 4, false);
+    bench_runner(&paths, 1, true);
+}
@@ -0,0 +1,640 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Configuration of an action, run when a rule or intent fires.
+///
+/// Actions are either written in shorthand, like `exit:32` or `exec:./script.sh:{rule}`
+/// where everything after the colon following the command is passed as one argument, or
+/// as a structured action like `{exec: {command: ./script.sh, args: ["{rule}"]}}`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum ActionConfig {
+    Short(String),
+    Full(ActionSpec),
+}
+
+// Deserialized by hand rather than untagged, so that errors within a structured action
+// name the bad field instead of "data did not match any variant".
+impl<'de> Deserialize<'de> for ActionConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ActionVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for ActionVisitor {
+            type Value = ActionConfig;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a shorthand action string or a map such as {exec: {...}}")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(ActionConfig::Short(v.to_string()))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let de = serde::de::value::MapAccessDeserializer::new(map);
+                Ok(ActionConfig::Full(ActionSpec::deserialize(de)?))
+            }
+        }
+
+        deserializer.deserialize_any(ActionVisitor)
+    }
+}
+
+impl From<&str> for ActionConfig {
+    fn from(s: &str) -> Self {
+        ActionConfig::Short(s.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum ActionSpec {
+    Exit(i32),
+    Exec(ExecConfig),
+    Http(HttpConfig),
+}
+
+/// Runs a command. The args, env values and stdin are templated, see [ActionContext].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExecConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Working directory of the command, defaults to the current directory.
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// Kill the command if it runs for longer than this.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Text written to the stdin of the command.
+    #[serde(default)]
+    pub stdin: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HttpMethod {
+    Get,
+    #[default]
+    Post,
+}
+
+/// Makes a HTTP request, such as calling a Home Assistant webhook. POST requests carry a
+/// JSON body describing the rule, the activations of each stage and any variables.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// The URL to request, which is templated.
+    pub url: String,
+    #[serde(default)]
+    pub method: HttpMethod,
+    /// Extra headers, the values of which are templated.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Name of an environment variable holding a token to send as `Authorization: Bearer`.
+    #[serde(default)]
+    pub bearer_token_env: Option<String>,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Number of times to retry a failed request.
+    #[serde(default)]
+    pub retries: Option<u32>,
+}
+
+/// The activation of a single stage of a rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageActivation {
+    /// Index of the stage within the chain of the rule.
+    pub stage: usize,
+    pub model: String,
+    pub score: f32,
+    pub timestamp: chrono::DateTime<chrono::Local>,
+}
+
+/// Describes why an action is being run, providing the values of template variables.
+///
+/// Templates can reference `{rule}`, `{model}`, `{score}`, `{timestamp}`, `{utterance}` and
+/// the name of any entry in vars, such as intent slots. The same values are also passed to
+/// executed commands as environment variables, such as `OWW_RULE`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ActionContext {
+    pub rule: String,
+    pub model: Option<String>,
+    pub score: Option<f32>,
+    pub timestamp: Option<chrono::DateTime<chrono::Local>>,
+    pub utterance: Option<PathBuf>,
+    pub vars: BTreeMap<String, String>,
+    /// The activations which caused a matcher rule to fire, in order.
+    pub chain: Vec<StageActivation>,
+    /// Arguments appended to executed commands after any configured args.
+    pub args: Vec<String>,
+}
+
+impl ActionContext {
+    pub fn new<S: Into<String>>(rule: S) -> Self {
+        Self {
+            rule: rule.into(),
+            timestamp: Some(chrono::Local::now()),
+            ..Self::default()
+        }
+    }
+
+    /// Returns all template variables and their values.
+    pub fn variables(&self) -> BTreeMap<String, String> {
+        let mut out = self.vars.clone();
+        out.insert("rule".to_string(), self.rule.clone());
+        let mut opt = |k: &str, v: Option<String>| {
+            out.insert(k.to_string(), v.unwrap_or_default());
+        };
+        opt("model", self.model.clone());
+        opt("score", self.score.map(|s| format!("{:.3}", s)));
+        opt("timestamp", self.timestamp.map(|t| t.to_rfc3339()));
+        opt(
+            "utterance",
+            self.utterance
+                .as_ref()
+                .map(|u| u.to_string_lossy().into_owned()),
+        );
+        out
+    }
+
+    /// Returns a JSON description of the context, used as the body of HTTP actions.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "rule": self.rule,
+            "model": self.model,
+            "score": self.score,
+            "timestamp": self.timestamp.map(|t| t.to_rfc3339()),
+            "utterance": self.utterance,
+            "vars": self.vars,
+            "chain": self.chain.iter().map(|a| serde_json::json!({
+                "stage": a.stage,
+                "model": a.model,
+                "score": a.score,
+                "timestamp": a.timestamp.to_rfc3339(),
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Substitutes `{variable}` references in s. Unknown references are left as-is.
+    pub fn render(&self, s: &str) -> String {
+        render(s, &self.variables())
+    }
+}
+
+/// Substitutes variables in a single pass, so values containing `{name}` are left as-is.
+fn render(s: &str, vars: &BTreeMap<String, String>) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after
+            .find('}')
+            .and_then(|end| vars.get(&after[..end]).map(|v| (end, v)))
+        {
+            Some((end, value)) => {
+                out.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// ActionHandler receives the actions of rules or intents when they fire, allowing
+/// applications to handle them in-process.
+pub trait ActionHandler: Send {
+    fn handle(&mut self, action: &Action, ctx: &ActionContext);
+}
+
+impl<F: FnMut(&Action, &ActionContext) + Send> ActionHandler for F {
+    fn handle(&mut self, action: &Action, ctx: &ActionContext) {
+        self(action, ctx)
+    }
+}
+
+/// Forwards actions to a channel, such as one read by the main loop of an application.
+impl ActionHandler for Sender<(Action, ActionContext)> {
+    fn handle(&mut self, action: &Action, ctx: &ActionContext) {
+        if let Err(e) = self.send((action.clone(), ctx.clone())) {
+            println!("{}: failed forwarding action: {:?}", ctx.rule, e);
+        }
+    }
+}
+
+/// A parsed action, ready to run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Exit(i32),
+    Exec(ExecConfig),
+    Http(HttpConfig),
+}
+
+impl TryFrom<&ActionConfig> for Action {
+    type Error = anyhow::Error;
+
+    fn try_from(config: &ActionConfig) -> Result<Self, Self::Error> {
+        match config {
+            ActionConfig::Full(ActionSpec::Exit(code)) => Ok(Action::Exit(*code)),
+            ActionConfig::Full(ActionSpec::Exec(exec)) => Ok(Action::Exec(exec.clone())),
+            ActionConfig::Full(ActionSpec::Http(http)) => Ok(Action::Http(http.clone())),
+            ActionConfig::Short(s) => {
+                let (kind, rest) = s.split_once(':').unwrap_or((s, ""));
+                match kind {
+                    "exit" => Ok(Action::Exit(if rest.is_empty() {
+                        0
+                    } else {
+                        rest.parse()
+                            .map_err(|e| anyhow::anyhow!("invalid exit code {:?}: {}", rest, e))?
+                    })),
+                    "exec" => {
+                        // Only the first colon separates the command from its argument,
+                        // so arguments such as URLs keep theirs.
+                        let (command, arg) = match rest.split_once(':') {
+                            Some((command, arg)) => (command, Some(arg)),
+                            None => (rest, None),
+                        };
+                        if command.is_empty() {
+                            anyhow::bail!("exec action is missing a command");
+                        }
+                        Ok(Action::Exec(ExecConfig {
+                            command: command.to_string(),
+                            args: arg.into_iter().map(String::from).collect(),
+                            env: BTreeMap::new(),
+                            cwd: None,
+                            timeout_ms: None,
+                            stdin: None,
+                        }))
+                    }
+                    // Allow both `http:https://host/path` and plain `http://host/path`.
+                    "http" | "https" => Ok(Action::Http(HttpConfig {
+                        url: if rest.starts_with("//") {
+                            s.to_string()
+                        } else {
+                            rest.to_string()
+                        },
+                        method: HttpMethod::default(),
+                        headers: BTreeMap::new(),
+                        bearer_token_env: None,
+                        timeout_ms: None,
+                        retries: None,
+                    })),
+                    _ => anyhow::bail!("unknown action type {:?} in {:?}", kind, s),
+                }
+            }
+        }
+    }
+}
+
+impl Action {
+    /// The exit code requested by this action, if it is an exit action.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            Action::Exit(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Runs the action in the background. Exit actions are not run, as only the
+    /// application can decide to exit; see [Action::exit_code].
+    pub fn run(&self, ctx: &ActionContext) {
+        match self {
+            Action::Exit(code) => {
+                println!("{}: ignoring request to exit with {}", ctx.rule, code);
+            }
+            Action::Exec(exec) => {
+                if let Err(e) = Self::exec(exec, ctx) {
+                    println!("{}: failed running {}: {:?}", ctx.rule, exec.command, e);
+                }
+            }
+            Action::Http(http) => {
+                let (http, ctx) = (http.clone(), ctx.clone());
+                thread::spawn(move || {
+                    if let Err(e) = Self::http(&http, &ctx) {
+                        println!("{}: request to {} failed: {:?}", ctx.rule, http.url, e);
+                    }
+                });
+            }
+        }
+    }
+
+    fn http(http: &HttpConfig, ctx: &ActionContext) -> Result<(), anyhow::Error> {
+        let vars = ctx.variables();
+        let url = render(&http.url, &vars);
+        let body = serde_json::to_vec(&ctx.to_json())?;
+        let token = match &http.bearer_token_env {
+            Some(var) => Some(
+                std::env::var(var)
+                    .map_err(|e| anyhow::anyhow!("reading bearer token from ${}: {}", var, e))?,
+            ),
+            None => None,
+        };
+
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(Duration::from_millis(http.timeout_ms.unwrap_or(5000))))
+            .build()
+            .into();
+
+        let mut headers: Vec<(String, String)> = http
+            .headers
+            .iter()
+            .map(|(k, v)| (k.clone(), render(v, &vars)))
+            .collect();
+        if let Some(token) = &token {
+            headers.push(("Authorization".to_string(), format!("Bearer {}", token)));
+        }
+
+        let mut attempt = 0;
+        loop {
+            let res = match http.method {
+                HttpMethod::Get => headers
+                    .iter()
+                    .fold(agent.get(&url), |req, (k, v)| req.header(k, v))
+                    .call(),
+                HttpMethod::Post => headers
+                    .iter()
+                    .fold(
+                        agent.post(&url).header("Content-Type", "application/json"),
+                        |req, (k, v)| req.header(k, v),
+                    )
+                    .send(&body[..]),
+            };
+            match res {
+                Ok(resp) => {
+                    println!("{}: {} returned {}", ctx.rule, url, resp.status());
+                    return Ok(());
+                }
+                Err(e) if attempt < http.retries.unwrap_or(0) => {
+                    attempt += 1;
+                    println!("{}: request to {} failed, retrying: {:?}", ctx.rule, url, e);
+                    thread::sleep(Duration::from_millis(250 * (1 << attempt.min(6))));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn exec(exec: &ExecConfig, ctx: &ActionContext) -> Result<(), anyhow::Error> {
+        let vars = ctx.variables();
+
+        let mut cmd = Command::new(&exec.command);
+        cmd.args(exec.args.iter().map(|a| render(a, &vars)))
+            .args(&ctx.args)
+            .envs(
+                vars.iter()
+                    .map(|(k, v)| (format!("OWW_{}", k.to_uppercase()), v)),
+            )
+            .envs(exec.env.iter().map(|(k, v)| (k, render(v, &vars))))
+            .stdin(if exec.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            });
+        match &exec.cwd {
+            Some(dir) => cmd.current_dir(render(dir, &vars)),
+            None => cmd.current_dir(std::env::current_dir()?),
+        };
+        println!("{}: spawning: {:?}", ctx.rule, &cmd);
+        let child = cmd.spawn()?;
+
+        let stdin = exec.stdin.as_ref().map(|s| render(s, &vars));
+        let timeout = exec.timeout_ms.map(Duration::from_millis);
+        let name = ctx.rule.clone();
+        let command = exec.command.clone();
+
+        // Feed stdin and reap the child in the background, so zombies don't accumulate.
+        thread::spawn(move || match Self::wait(child, stdin, timeout) {
+            Ok(Some(status)) if status.success() => println!("{}: {} exited", name, command),
+            Ok(Some(status)) => println!("{}: {} exited with {}", name, command, status),
+            Ok(None) => println!("{}: {} timed out, killed", name, command),
+            Err(e) => println!("{}: failed waiting on {}: {:?}", name, command, e),
+        });
+        Ok(())
+    }
+
+    /// Writes stdin to a child and waits for it to exit, returning None if it was killed
+    /// after timeout. Stdin is written from another thread, so a child which doesn't read
+    /// it is still killed.
+    fn wait(
+        mut child: Child,
+        stdin: Option<String>,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<Option<ExitStatus>> {
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            // Writing fails once the child exits, ending the thread.
+            thread::spawn(move || pipe.write_all(input.as_bytes()));
+        }
+
+        let Some(timeout) = timeout else {
+            return child.wait().map(Some);
+        };
+        let started = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+            if started.elapsed() > timeout {
+                child.kill().ok();
+                child.wait()?;
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_substitutes_once() {
+        let mut ctx = ActionContext::new("lights");
+        ctx.vars
+            .insert("transcript".to_string(), "say {rule} {unknown".to_string());
+        assert_eq!(
+            ctx.render("{rule}: {transcript} {missing} {{rule}}"),
+            "lights: say {rule} {unknown {missing} {lights}"
+        );
+    }
+
+    #[test]
+    fn exec_shorthand_splits_on_the_first_colon() {
+        let exec = |s: &str| match Action::try_from(&ActionConfig::from(s)).unwrap() {
+            Action::Exec(exec) => (exec.command, exec.args),
+            action => panic!("expected an exec action, got {:?}", action),
+        };
+        assert_eq!(
+            exec("exec:./script.sh"),
+            ("./script.sh".to_string(), vec![])
+        );
+        assert_eq!(
+            exec("exec:./notify.sh:http://localhost:8123/{rule}"),
+            (
+                "./notify.sh".to_string(),
+                vec!["http://localhost:8123/{rule}".to_string()]
+            )
+        );
+        assert!(Action::try_from(&ActionConfig::from("exec:")).is_err());
+        assert!(Action::try_from(&ActionConfig::from("exec::arg")).is_err());
+    }
+
+    #[test]
+    fn timeout_kills_child_not_reading_stdin() {
+        let child = Command::new("sleep")
+            .arg("10")
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Far more than a pipe buffers, so writing it blocks until the child is killed.
+        let input = "x".repeat(1 << 22);
+        let started = Instant::now();
+        let status = Action::wait(child, Some(input), Some(Duration::from_millis(200))).unwrap();
+        assert_eq!(status, None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    /// Accepts a connection for each status, replying to each request with that status,
+    /// and returns the requests. A status of 0 accepts the request but never replies.
+    fn http_stub(statuses: &'static [u16]) -> (String, thread::JoinHandle<Vec<String>>) {
+        use std::io::{BufRead, BufReader, Read};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some((k, v)) = line.split_once(':')
+                        && k.eq_ignore_ascii_case("content-length")
+                    {
+                        content_length = v.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut content = vec![0u8; content_length];
+                reader.read_exact(&mut content).unwrap();
+                request.push_str(&String::from_utf8(content).unwrap());
+                requests.push(request);
+
+                if *status == 0 {
+                    // Hold the connection open until the client gives up.
+                    reader.read_to_end(&mut Vec::new()).ok();
+                    continue;
+                }
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn http_config(url: String) -> HttpConfig {
+        HttpConfig {
+            url,
+            method: HttpMethod::Post,
+            headers: BTreeMap::new(),
+            bearer_token_env: None,
+            timeout_ms: Some(2000),
+            retries: None,
+        }
+    }
+
+    #[test]
+    fn http_posts_context_with_headers_and_token() {
+        let (url, stub) = http_stub(&[200]);
+        let var = format!("OWW_TEST_TOKEN_{}", std::process::id());
+        // SAFETY: no other test reads or writes this variable.
+        unsafe { std::env::set_var(&var, "secret") };
+        let mut http = http_config(format!("{}?rule={{rule}}", url));
+        http.headers
+            .insert("X-Model".to_string(), "{model}".to_string());
+        http.bearer_token_env = Some(var);
+
+        let mut ctx = ActionContext::new("lights");
+        ctx.model = Some("wakeword".to_string());
+        ctx.score = Some(0.9);
+        ctx.vars.insert("room".to_string(), "kitchen".to_string());
+        Action::http(&http, &ctx).unwrap();
+
+        let request = stub.join().unwrap().remove(0);
+        let lower = request.to_lowercase();
+        assert!(
+            request.starts_with("POST /hook?rule=lights HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+        assert!(lower.contains("authorization: bearer secret\r\n"));
+        assert!(lower.contains("x-model: wakeword\r\n"));
+        assert!(lower.contains("content-type: application/json\r\n"));
+        let body = request.split_once("\r\n\r\n").unwrap().1;
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body, ctx.to_json());
+    }
+
+    #[test]
+    fn http_requires_its_token() {
+        let mut http = http_config("http://127.0.0.1:9/hook".to_string());
+        http.bearer_token_env = Some("OWW_TEST_TOKEN_UNSET".to_string());
+        let err = Action::http(&http, &ActionContext::new("lights")).unwrap_err();
+        assert!(err.to_string().contains("$OWW_TEST_TOKEN_UNSET"), "{}", err);
+    }
+
+    #[test]
+    fn http_retries_failed_requests() {
+        let (url, stub) = http_stub(&[500, 503, 200]);
+        let mut http = http_config(url.clone());
+        http.retries = Some(2);
+        Action::http(&http, &ActionContext::new("lights")).unwrap();
+        assert_eq!(stub.join().unwrap().len(), 3);
+
+        // Without enough retries, the last failure is returned.
+        let (url, stub) = http_stub(&[500, 500]);
+        let mut http = http_config(url);
+        http.retries = Some(1);
+        assert!(Action::http(&http, &ActionContext::new("lights")).is_err());
+        assert_eq!(stub.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn http_times_out() {
+        let (url, _stub) = http_stub(&[0]);
+        let mut http = http_config(url);
+        http.timeout_ms = Some(200);
+        let started = Instant::now();
+        assert!(Action::http(&http, &ActionContext::new("lights")).is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
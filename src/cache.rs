@@ -0,0 +1,158 @@
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::ModelSource;
+use crate::source::nnef;
+
+/// ModelCache stores models in tract's NNEF format once they have been imported and
+/// simplified, so later starts can skip both steps. Models are still optimized for the
+/// host when loaded, as tract can't store optimized models. Entries are keyed by a hash
+/// of the source model and the input shape it is loaded with, so a changed model is
+/// compiled again rather than served stale.
+#[derive(Debug, Clone)]
+pub struct ModelCache {
+    dir: PathBuf,
+}
+
+impl ModelCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Returns the cached model for source, compiling it first if it isn't cached yet.
+    pub fn get(
+        &self,
+        source: ModelSource,
+        input_shape: Option<&[usize]>,
+    ) -> Result<ModelSource<'static>, anyhow::Error> {
+        let bytes = source.read()?;
+        let path = self.path(&bytes, input_shape);
+        if !path.is_file() {
+            self.write(&bytes, input_shape, &path)?;
+        }
+        Ok(ModelSource::Path(path))
+    }
+
+    /// Compiles source into the cache, replacing any existing entry, and returns the path
+    /// of the cached model.
+    pub fn compile(
+        &self,
+        source: ModelSource,
+        input_shape: Option<&[usize]>,
+    ) -> Result<PathBuf, anyhow::Error> {
+        let bytes = source.read()?;
+        let path = self.path(&bytes, input_shape);
+        self.write(&bytes, input_shape, &path)?;
+        Ok(path)
+    }
+
+    /// The cache entry for a model. The crate version is part of the key as the NNEF
+    /// written by one version of tract may not load in another.
+    fn path(&self, bytes: &[u8], input_shape: Option<&[usize]>) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(env!("CARGO_PKG_VERSION"));
+        hasher.update(format!("{:?}", input_shape));
+        hasher.update(bytes);
+        let name: String = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.dir.join(format!("{}.nnef.tar", name))
+    }
+
+    fn write(
+        &self,
+        bytes: &[u8],
+        input_shape: Option<&[usize]>,
+        path: &Path,
+    ) -> Result<(), anyhow::Error> {
+        let model = ModelSource::Bytes(bytes)
+            .typed(input_shape)?
+            .into_decluttered()?;
+        std::fs::create_dir_all(&self.dir)
+            .map_err(|e| anyhow::anyhow!("creating {:?}: {}", self.dir, e))?;
+
+        // Write to a temporary file first so an interrupted write is never loaded.
+        let tmp = path.with_extension("tmp");
+        let f = std::fs::File::create(&tmp)
+            .map_err(|e| anyhow::anyhow!("creating {:?}: {}", tmp, e))?;
+        nnef()
+            .write_to_tar(&model, std::io::BufWriter::new(f))?
+            .into_inner()?
+            .sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EMBEDDING_INPUT_SHAPE, Embedding, ModelConfig, NamedModel};
+    use tract_onnx::prelude::*;
+
+    const MODEL: &str = "models/hey_rhasspy_v0.1.onnx";
+
+    fn test_cache(name: &str) -> (ModelCache, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("oww-cache-{}-{}", std::process::id(), name));
+        std::fs::remove_dir_all(&dir).ok();
+        (ModelCache::new(&dir), dir)
+    }
+
+    #[test]
+    fn cached_models_score_as_their_source() {
+        let (cache, dir) = test_cache("scores");
+        let embeddings: Vec<Embedding> = (0..40)
+            .map(|i| {
+                let mut values = [0.; 96];
+                for (j, v) in values.iter_mut().enumerate() {
+                    *v = (((i * 31 + j * 17) % 23) as f32 - 11.) / 3.;
+                }
+                values.into()
+            })
+            .collect();
+
+        let source = NamedModel::new("source", MODEL, 1.).unwrap();
+        let config: ModelConfig = serde_yaml::from_str(&format!("path: {}", MODEL)).unwrap();
+        let cached = NamedModel::from_config("cached", &config, Some(&cache)).unwrap();
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+
+        let (a, b) = (source.scores(&embeddings), cached.scores(&embeddings));
+        assert_eq!(a.len(), b.len());
+        assert!(a.iter().any(|s| *s > 0.01 && *s < 0.99), "{:?}", a);
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn cached_feature_models_keep_their_input_shape() {
+        let (cache, dir) = test_cache("features");
+        let shape = Some(&EMBEDDING_INPUT_SHAPE[..]);
+        let path = cache.compile(ModelSource::embedding(), shape).unwrap();
+        // Later starts find the compiled entry.
+        match cache.get(ModelSource::embedding(), shape).unwrap() {
+            ModelSource::Path(cached) => assert_eq!(cached, path),
+            _ => panic!("expected a cached path"),
+        }
+
+        let len: usize = EMBEDDING_INPUT_SHAPE.iter().product();
+        let values: Vec<f32> = (0..len).map(|i| (i as f32 * 0.1).sin()).collect();
+        let input = Tensor::from_shape(&EMBEDDING_INPUT_SHAPE, &values).unwrap();
+        let run = |source: ModelSource| {
+            let model = source.load(shape).unwrap();
+            let out = model.run(tvec!(input.clone().into())).unwrap().remove(0);
+            out.as_slice::<f32>().unwrap().to_vec()
+        };
+        let (a, b) = (run(ModelSource::embedding()), run(ModelSource::Path(path)));
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
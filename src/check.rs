@@ -0,0 +1,386 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::intent::compile_template;
+use crate::utterance::check_retention;
+use crate::{Action, Config, EMBEDDING_INPUT_SHAPE, MatchStageConfig, NamedModel, Verifier};
+
+/// A problem found in a config, along with the YAML path of the offending value such
+/// as `matchers.lights.chain[1].model`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Default)]
+struct Errors(Vec<ConfigError>);
+
+impl Errors {
+    fn push<P: Into<String>, M: fmt::Display>(&mut self, path: P, message: M) {
+        self.0.push(ConfigError {
+            path: path.into(),
+            message: message.to_string(),
+        });
+    }
+
+    fn check_model(&mut self, config: &Config, path: String, model: &str) {
+        if !config.models.contains_key(model) {
+            self.push(path, format!("unknown model {:?}", model));
+        }
+    }
+
+    fn check_threshold(&mut self, path: String, threshold: Option<f32>) {
+        if let Some(t) = threshold
+            && !(0.0..=1.0).contains(&t)
+        {
+            self.push(path, format!("must be between 0 and 1, got {}", t));
+        }
+    }
+
+    fn check_stage(&mut self, config: &Config, path: &str, stage: &MatchStageConfig) {
+        let kinds = [
+            stage.model.is_some(),
+            !stage.any_of.is_empty(),
+            !stage.all_of.is_empty(),
+        ];
+        if kinds.iter().filter(|k| **k).count() != 1 {
+            self.push(
+                path,
+                "exactly one of model, any_of or all_of must be specified",
+            );
+        }
+
+        if let Some(model) = &stage.model {
+            self.check_model(config, format!("{}.model", path), model);
+        }
+        for (i, model) in stage.any_of.iter().enumerate() {
+            self.check_model(config, format!("{}.any_of[{}]", path, i), model);
+        }
+        for (i, model) in stage.all_of.iter().enumerate() {
+            self.check_model(config, format!("{}.all_of[{}]", path, i), model);
+        }
+        if stage.window_ms.is_some() && stage.all_of.is_empty() {
+            self.push(
+                format!("{}.window_ms", path),
+                "only applies to all_of stages",
+            );
+        }
+        self.check_threshold(
+            format!("{}.activation_threshold", path),
+            stage.activation_threshold,
+        );
+
+        for (i, inhibitor) in stage.unless.iter().enumerate() {
+            let path = format!("{}.unless[{}]", path, i);
+            self.check_model(config, format!("{}.model", path), &inhibitor.model);
+            self.check_threshold(
+                format!("{}.activation_threshold", path),
+                inhibitor.activation_threshold,
+            );
+        }
+    }
+}
+
+impl Config {
+    /// Reads and parses a YAML config file, rejecting unknown fields. Errors name the
+    /// YAML path of the offending value.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        let f = std::fs::File::open(path.as_ref())
+            .map_err(|e| anyhow::anyhow!("opening {:?}: {}", path.as_ref(), e))?;
+        Ok(serde_yaml::from_reader(std::io::BufReader::new(f))?)
+    }
+
+    /// Checks references between sections, value ranges and action syntax, returning
+    /// every problem found. Models are loaded if load_models is set, which catches
+    /// missing or invalid model files.
+    pub fn validate(&self, load_models: bool) -> Vec<ConfigError> {
+        let mut errs = Errors::default();
+
+        for (name, model) in self.models.iter() {
+            if let Some(scale) = model.scale
+                && !(scale.is_finite() && scale > 0.)
+            {
+                errs.push(
+                    format!("models.{}.scale", name),
+                    format!("must be positive, got {}", scale),
+                );
+            }
+            if let Some(verifier) = &model.verifier {
+                errs.check_threshold(
+                    format!("models.{}.verifier.threshold", name),
+                    verifier.threshold,
+                );
+            }
+            if !load_models {
+                continue;
+            }
+            let loaded = match NamedModel::new(name.as_str(), model.path.as_str(), 1.) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    errs.push(
+                        format!("models.{}.path", name),
+                        format!("failed loading {:?}: {:#}", model.path, e),
+                    );
+                    continue;
+                }
+            };
+            if let Some(verifier) = &model.verifier {
+                match Verifier::load(&verifier.path) {
+                    Ok(v) => {
+                        if let Err(e) = loaded.with_verifier(v, 0.5) {
+                            errs.push(format!("models.{}.verifier", name), e);
+                        }
+                    }
+                    Err(e) => errs.push(format!("models.{}.verifier.path", name), e),
+                }
+            }
+        }
+
+        if load_models {
+            if let Err(e) = self.features.melspectrogram().load(None) {
+                errs.push(
+                    "features.melspectrogram",
+                    format!("failed loading: {:#}", e),
+                );
+            }
+            if let Err(e) = self.features.embedding().load(Some(&EMBEDDING_INPUT_SHAPE)) {
+                errs.push("features.embedding", format!("failed loading: {:#}", e));
+            }
+        }
+
+        for (name, rule) in self.matchers.iter() {
+            if rule.chain.is_empty() {
+                errs.push(format!("matchers.{}.chain", name), "must not be empty");
+            }
+            for (i, stage) in rule.chain.iter().enumerate() {
+                errs.check_stage(self, &format!("matchers.{}.chain[{}]", name, i), stage);
+            }
+            if let Err(e) = Action::try_from(&rule.action) {
+                errs.push(format!("matchers.{}.action", name), e);
+            }
+        }
+
+        if let Some(wakeword) = &self.utterance.wakeword {
+            errs.check_model(self, "utterance.wakeword".to_string(), wakeword);
+        }
+        if let Some(action) = &self.utterance.action
+            && let Err(e) = Action::try_from(action)
+        {
+            errs.push("utterance.action", e);
+        }
+        if self
+            .utterance
+            .exec
+            .as_ref()
+            .is_some_and(|cmd| shlex::split(cmd).is_none_or(|args| args.is_empty()))
+        {
+            errs.push("utterance.exec", "invalid or empty command");
+        }
+        if let Err(e) = check_retention(&self.utterance) {
+            errs.push("utterance.retention", e);
+        }
+        if let Some(transcriber) = &self.utterance.transcriber
+            && let Err(e) = transcriber.build()
+        {
+            errs.push("utterance.transcriber", e);
+        }
+
+        for (name, intent) in self.intents.iter() {
+            if intent.sentences.is_empty() && intent.regexes.is_empty() {
+                errs.push(
+                    format!("intents.{}", name),
+                    "at least one of sentences or regexes must be specified",
+                );
+            }
+            for (i, sentence) in intent.sentences.iter().enumerate() {
+                if let Err(e) = compile_template(sentence, &intent.slots) {
+                    errs.push(format!("intents.{}.sentences[{}]", name, i), e);
+                }
+            }
+            for (i, re) in intent.regexes.iter().enumerate() {
+                if let Err(e) = regex::Regex::new(re) {
+                    errs.push(format!("intents.{}.regexes[{}]", name, i), e);
+                }
+            }
+            if let Err(e) = Action::try_from(&intent.action) {
+                errs.push(format!("intents.{}.action", name), e);
+            }
+
+            // Sentences may reference slots without values, but values for a slot no
+            // sentence references are likely a typo.
+            for slot in intent.slots.keys() {
+                let placeholder = format!("{{{}}}", slot);
+                if !intent.sentences.iter().any(|s| s.contains(&placeholder)) {
+                    errs.push(
+                        format!("intents.{}.slots.{}", name, slot),
+                        "not used by any sentence",
+                    );
+                }
+            }
+        }
+
+        errs.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ActionConfig, ActionSpec};
+
+    fn parse(yaml: &str) -> Result<Config, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    const MODELS: &str = "models:\n  wakeword:\n    path: models/wakeword.onnx\n";
+
+    #[test]
+    fn actions_parse_in_both_forms() {
+        let config = parse(&format!(
+            "{}matchers:\n  a:\n    chain: [{{model: wakeword}}]\n    action: exit:32\n  b:\n    chain: [{{model: wakeword}}]\n    action: {{exec: {{command: ./run.sh, args: [\"{{rule}}\"]}}}}\n",
+            MODELS
+        ))
+        .unwrap();
+        assert_eq!(config.matchers["a"].action, ActionConfig::from("exit:32"));
+        assert!(matches!(
+            &config.matchers["b"].action,
+            ActionConfig::Full(ActionSpec::Exec(e)) if e.command == "./run.sh"
+        ));
+    }
+
+    #[test]
+    fn errors_name_the_path_of_the_bad_field() {
+        let err = parse(&format!(
+            "{}matchers:\n  a:\n    chain: [{{model: wakeword}}]\n    action: {{exec: {{command: ./run.sh, timout_ms: 10}}}}\n",
+            MODELS
+        ))
+        .unwrap_err()
+        .to_string();
+        assert!(err.starts_with("matchers.a.action.exec: "), "{}", err);
+        assert!(err.contains("unknown field `timout_ms`"), "{}", err);
+
+        let err = parse("models:\n  wakeword:\n    path: a.onnx\n    scal: 1\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("models.wakeword: "), "{}", err);
+        assert!(err.contains("unknown field `scal`"), "{}", err);
+    }
+
+    /// Validates a config without loading models, returning the path and message of
+    /// each error.
+    fn validate(yaml: &str) -> Vec<(String, String)> {
+        parse(yaml)
+            .unwrap()
+            .validate(false)
+            .into_iter()
+            .map(|e| (e.path, e.message))
+            .collect()
+    }
+
+    fn rule(chain: &str, action: &str) -> String {
+        format!(
+            "{}matchers:\n  lights:\n    chain: {}\n    action: {}\n",
+            MODELS, chain, action
+        )
+    }
+
+    #[test]
+    fn validate_accepts_a_good_config() {
+        let chain = "[{model: wakeword}, {any_of: [wakeword], unless: [{model: wakeword}]}]";
+        assert_eq!(validate(&rule(chain, "exit:0")), []);
+    }
+
+    #[test]
+    fn validate_reports_unknown_models() {
+        let chain = "[{model: wakewrd}, {all_of: [wakeword, other], unless: [{model: noise}]}]";
+        let paths: Vec<_> = validate(&rule(chain, "exit:0"))
+            .into_iter()
+            .map(|(path, message)| {
+                assert!(message.starts_with("unknown model "), "{}", message);
+                path
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "matchers.lights.chain[0].model",
+                "matchers.lights.chain[1].all_of[1]",
+                "matchers.lights.chain[1].unless[0].model",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_reports_bad_thresholds() {
+        let chain = "[{model: wakeword, activation_threshold: 1.5, unless: [{model: wakeword, activation_threshold: -1}]}]";
+        let errs = validate(&rule(chain, "exit:0"));
+        assert_eq!(
+            errs,
+            [
+                (
+                    "matchers.lights.chain[0].activation_threshold".to_string(),
+                    "must be between 0 and 1, got 1.5".to_string()
+                ),
+                (
+                    "matchers.lights.chain[0].unless[0].activation_threshold".to_string(),
+                    "must be between 0 and 1, got -1".to_string()
+                ),
+            ]
+        );
+
+        let errs = validate(
+            "models:\n  wakeword:\n    path: a.onnx\n    verifier: {path: v.json, threshold: 2}\nmatchers: {}\n",
+        );
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].0, "models.wakeword.verifier.threshold");
+    }
+
+    #[test]
+    fn validate_reports_bad_scales() {
+        for scale in ["0", "-1", ".nan"] {
+            let errs = validate(&format!(
+                "models:\n  wakeword:\n    path: a.onnx\n    scale: {}\nmatchers: {{}}\n",
+                scale
+            ));
+            assert_eq!(errs.len(), 1, "{}", scale);
+            assert_eq!(errs[0].0, "models.wakeword.scale");
+            assert!(errs[0].1.starts_with("must be positive"), "{}", errs[0].1);
+        }
+    }
+
+    #[test]
+    fn validate_reports_unknown_action_types() {
+        let errs = validate(&rule("[{model: wakeword}]", "launch:rocket"));
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].0, "matchers.lights.action");
+        assert!(
+            errs[0].1.contains("unknown action type \"launch\""),
+            "{}",
+            errs[0].1
+        );
+    }
+
+    #[test]
+    fn validate_reports_missing_wakeword() {
+        let errs = validate(&format!(
+            "{}matchers: {{}}\nutterance:\n  wakeword: hey_computer\n",
+            MODELS
+        ));
+        assert_eq!(
+            errs,
+            [(
+                "utterance.wakeword".to_string(),
+                "unknown model \"hey_computer\"".to_string()
+            )]
+        );
+    }
+}
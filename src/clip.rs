@@ -0,0 +1,81 @@
+use std::path::Path;
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+use crate::sampler::SAMPLE_RATE;
+use crate::{
+    Chunk, Embedder, Embedding, ModelSource, NUM_SPECTOGRAMS, SPECTOGRAM_SAMPLES,
+    SPECTOGRAMS_PER_CHUNK, Specter,
+};
+
+/// Reads a mono WAV file recorded at the sample rate of the pipeline, scaling samples by
+/// preamp as [crate::Sampler] does.
+pub fn read_wav<P: AsRef<Path>>(path: P, preamp: f32) -> Result<Vec<f32>, anyhow::Error> {
+    let path = path.as_ref();
+    let mut wav = wavers::Wav::<f32>::from_path(path)
+        .map_err(|e| anyhow::anyhow!("opening {:?}: {}", path, e))?;
+    if wav.n_channels() != 1 || wav.sample_rate() != SAMPLE_RATE as i32 {
+        anyhow::bail!(
+            "{:?}: expected mono audio at {} Hz, found {} channel(s) at {} Hz",
+            path,
+            SAMPLE_RATE,
+            wav.n_channels(),
+            wav.sample_rate()
+        );
+    }
+    Ok(wav.read()?.iter().map(|s| s * preamp).collect())
+}
+
+/// Silence led into clips, enough for the melspectograms of a full embedding input.
+const LEAD: usize = NUM_SPECTOGRAMS.div_ceil(SPECTOGRAMS_PER_CHUNK) * SPECTOGRAM_SAMPLES;
+
+/// The number of samples of a clip passed to [embed_clip] that the embedding at index
+/// covers, as each embedding covers the most recent audio when it is computed.
+pub fn embedding_end(index: usize, step_interval: usize) -> usize {
+    // The first melspectograms are of the second chunk, as the specter overlaps each
+    // chunk with the next.
+    let hop = SPECTOGRAM_SAMPLES / SPECTOGRAMS_PER_CHUNK;
+    let spectograms = NUM_SPECTOGRAMS.next_multiple_of(step_interval) + index * step_interval;
+    (SPECTOGRAM_SAMPLES + spectograms * hop).saturating_sub(LEAD)
+}
+
+/// Computes the embeddings of a recorded clip with a [Specter] and [Embedder], as they
+/// would be computed while listening. The clip is led by enough silence to fill the input
+/// of the embedding model, so embeddings cover its start, and followed by a little more so
+/// its end is processed.
+pub fn embed_clip(
+    samples: &[f32],
+    melspectrogram: ModelSource,
+    embedding: ModelSource,
+    step_interval: usize,
+) -> Result<Vec<Embedding>, anyhow::Error> {
+    let (tx, rx) = sync_channel(1);
+    let mut specter = Specter::start_with_model(rx, melspectrogram)?;
+    let mut embedder =
+        Embedder::start_with_model(specter.take_receiver().unwrap(), step_interval, embedding)?;
+    let embeddings = embedder.take_receiver().unwrap();
+
+    let trail = 2 * SPECTOGRAM_SAMPLES;
+    thread::scope(|s| {
+        // Chunks are sent from another thread, as the stages only buffer a frame each.
+        s.spawn(move || {
+            let padded: Vec<f32> = std::iter::repeat_n(0., LEAD)
+                .chain(samples.iter().copied())
+                .chain(std::iter::repeat_n(0., trail))
+                .collect();
+            for (id, c) in padded.chunks(SPECTOGRAM_SAMPLES).enumerate() {
+                let mut chunk = Chunk {
+                    id: id as u64,
+                    samples: [0.; SPECTOGRAM_SAMPLES],
+                };
+                chunk.samples[..c.len()].copy_from_slice(c);
+                if tx.send(chunk).is_err() {
+                    return;
+                }
+            }
+        });
+
+        // The stages shut down once every chunk has been processed, closing the channel.
+        Ok(embeddings.iter().collect())
+    })
+}
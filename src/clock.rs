@@ -0,0 +1,131 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::sampler::SAMPLE_RATE;
+
+/// Clock provides the current time to components which measure timeouts, such as the
+/// [crate::Matcher]. Times are only compared with each other, so they may start anywhere.
+pub trait Clock: Send {
+    fn now(&self) -> Duration;
+
+    /// The local time at a time returned by [Clock::now], used to timestamp activations.
+    fn timestamp(&self, at: Duration) -> chrono::DateTime<chrono::Local>;
+}
+
+/// WallClock measures real time since it was created.
+#[derive(Clone, Debug)]
+pub struct WallClock {
+    start: Instant,
+    started_at: chrono::DateTime<chrono::Local>,
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            started_at: chrono::Local::now(),
+        }
+    }
+}
+
+impl Clock for WallClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn timestamp(&self, at: Duration) -> chrono::DateTime<chrono::Local> {
+        self.started_at + at
+    }
+}
+
+/// StreamClock measures time by the number of audio samples processed, so that timing
+/// depends only on the audio and not on how quickly it is processed. Clones share the
+/// same position, so one can be handed to a [crate::Matcher] and another advanced as
+/// audio is consumed.
+#[derive(Clone, Debug)]
+pub struct StreamClock {
+    samples: Arc<AtomicU64>,
+    started_at: chrono::DateTime<chrono::Local>,
+}
+
+impl Default for StreamClock {
+    fn default() -> Self {
+        Self::starting_at(chrono::Local::now())
+    }
+}
+
+impl StreamClock {
+    /// Creates a clock for a stream starting now.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a clock for a stream which started at started_at, such as a recording
+    /// being replayed, so timestamps are those of the audio.
+    pub fn starting_at(started_at: chrono::DateTime<chrono::Local>) -> Self {
+        Self {
+            samples: Arc::new(AtomicU64::new(0)),
+            started_at,
+        }
+    }
+
+    /// Moves the clock forward by the duration of the given number of samples.
+    pub fn advance(&self, samples: u64) {
+        self.samples.fetch_add(samples, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, samples: u64) {
+        self.samples.store(samples, Ordering::SeqCst);
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples.load(Ordering::SeqCst)
+    }
+}
+
+impl Clock for StreamClock {
+    fn now(&self) -> Duration {
+        Duration::from_micros(self.samples() * 1_000_000 / SAMPLE_RATE as u64)
+    }
+
+    fn timestamp(&self, at: Duration) -> chrono::DateTime<chrono::Local> {
+        self.started_at + at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_clock_follows_samples() {
+        let clock = StreamClock::new();
+        let shared = clock.clone();
+        assert_eq!(clock.now(), Duration::ZERO);
+
+        // One 64ms frame of 1024 samples at a time, as the runner produces results.
+        for frame in 1..=25u64 {
+            clock.advance(1024);
+            assert_eq!(shared.now(), Duration::from_millis(frame * 64));
+        }
+        assert_eq!(shared.samples(), 25_600);
+        assert_eq!(shared.now(), Duration::from_millis(1600));
+
+        shared.set(8_000);
+        assert_eq!(clock.now(), Duration::from_millis(500));
+        clock.advance(1);
+        assert_eq!(clock.now(), Duration::from_micros(500_062));
+    }
+
+    #[test]
+    fn stream_clock_timestamps_follow_the_audio() {
+        let started_at = chrono::Local::now() - chrono::Duration::days(1);
+        let clock = StreamClock::starting_at(started_at);
+        clock.advance(SAMPLE_RATE as u64 * 3);
+        assert_eq!(
+            clock.timestamp(clock.now()),
+            started_at + chrono::Duration::seconds(3)
+        );
+    }
+}
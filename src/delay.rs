@@ -55,11 +55,11 @@ impl<const S: usize, const D: usize> Delay<S, D> {
 
             if queue.len() >= D {
                 let out = queue.pop_front().unwrap();
-                if let Err(e) = tx.try_send(out) {
-                    if matches!(e, TrySendError::Disconnected(_)) {
-                        println!("failed send, delay thread shutting down! {:?}", e);
-                        return;
-                    }
+                if let Err(e) = tx.try_send(out)
+                    && matches!(e, TrySendError::Disconnected(_))
+                {
+                    println!("failed send, delay thread shutting down! {:?}", e);
+                    return;
                 }
             }
         }
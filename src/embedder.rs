@@ -2,32 +2,84 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
 use std::thread;
+use std::time::Duration;
 
-use crate::Melspectogram;
+use crate::sampler::SAMPLE_RATE;
+use crate::tee::Taps;
+use crate::{Melspectogram, ModelSource};
 use circular_buffer::CircularBuffer;
 use tract_onnx::prelude::*;
 
 pub const NUM_SPECTOGRAMS: usize = 76;
 
+/// Input shape of the embedding model.
+pub const EMBEDDING_INPUT_SHAPE: [usize; 4] = [1, NUM_SPECTOGRAMS, 32, 1];
+
+/// Embedding is the output of the embedding model for a window of melspectograms,
+/// numbered in order from the start of the stream along with the position of the audio
+/// it covers.
 #[derive(Clone, Debug)]
-pub struct Embedding([f32; 96]);
+pub struct Embedding {
+    values: [f32; 96],
+    id: u64,
+    sample: u64,
+}
 
 // derive(Default) doesnt work on arrays > 32, grrrr
 impl Default for Embedding {
     fn default() -> Self {
-        Self([0f32; 96])
+        Self::from([0f32; 96])
+    }
+}
+
+/// Creates an embedding with id 0, ending at sample 0, for values which aren't from a
+/// stream. Use [Embedding::new] to place it in one.
+impl From<[f32; 96]> for Embedding {
+    fn from(values: [f32; 96]) -> Self {
+        Self {
+            values,
+            id: 0,
+            sample: 0,
+        }
     }
 }
 
 impl Embedding {
+    /// Creates an embedding with the given id, ending sample samples into the stream.
+    pub fn new(values: [f32; 96], id: u64, sample: u64) -> Self {
+        Self { values, id, sample }
+    }
+
     pub fn iter(&self) -> core::slice::Iter<'_, f32> {
-        self.0.iter()
+        self.values.iter()
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.values
+    }
+
+    /// The index of the embedding since the start of the stream.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The number of samples into the stream that the audio it covers ends: that of its
+    /// last melspectogram.
+    pub fn sample(&self) -> u64 {
+        self.sample
+    }
+
+    /// The time into the stream that the audio it covers ends, as a [crate::StreamClock]
+    /// would measure it.
+    pub fn timestamp(&self) -> Duration {
+        Duration::from_micros(self.sample * 1_000_000 / SAMPLE_RATE as u64)
     }
 }
 
 /// Embedder collects chunks of melspectograms and outputs embeddings.
 pub struct Embedder {
     recv: Option<Receiver<Embedding>>,
+    taps: Taps<Embedding>,
     shutdown: Arc<AtomicBool>,
     thread: Option<thread::JoinHandle<()>>,
 }
@@ -37,23 +89,30 @@ impl Embedder {
         spectos: Receiver<Vec<Melspectogram>>,
         step_interval: usize,
     ) -> Result<Self, anyhow::Error> {
-        let emb_model = tract_onnx::onnx()
-            // load the model
-            .model_for_path("embedding_model.onnx")?
-            .with_input_fact(0, f32::fact([1, 76, 32, 1]).into())
-            .unwrap()
-            .into_optimized()?
-            .into_runnable()?;
+        Self::start_with_model(spectos, step_interval, ModelSource::embedding())
+    }
+
+    /// Starts an embedder using the embedding model from source.
+    pub fn start_with_model(
+        spectos: Receiver<Vec<Melspectogram>>,
+        step_interval: usize,
+        source: ModelSource,
+    ) -> Result<Self, anyhow::Error> {
+        let emb_model = source.load(Some(&EMBEDDING_INPUT_SHAPE))?;
 
         let (send, recv) = sync_channel(1);
         let shutdown = Arc::new(AtomicBool::new(false));
 
+        let taps = Taps::new();
+
         let shutdown2 = shutdown.clone();
+        let taps2 = taps.clone();
         let thread = Some(thread::spawn(move || {
-            Embedder::mainloop(send, shutdown2, spectos, emb_model, step_interval);
+            Embedder::mainloop(send, taps2, shutdown2, spectos, emb_model, step_interval);
         }));
 
         let out = Self {
+            taps,
             shutdown,
             thread,
             recv: Some(recv),
@@ -66,8 +125,16 @@ impl Embedder {
         self.recv.take()
     }
 
+    /// Returns another receiver of the embeddings, so they can be used alongside the
+    /// pipeline without being computed twice. Up to bound embeddings (at least one) are
+    /// buffered, after which embeddings are dropped until the receiver catches up.
+    pub fn tap(&self, bound: usize) -> Receiver<Embedding> {
+        self.taps.add(bound)
+    }
+
     fn mainloop(
         tx: SyncSender<Embedding>,
+        taps: Taps<Embedding>,
         shutdown: Arc<AtomicBool>,
         spectos: Receiver<Vec<Melspectogram>>,
         emb_model: TypedRunnableModel<TypedModel>,
@@ -75,6 +142,7 @@ impl Embedder {
     ) {
         let mut spectograms = CircularBuffer::<NUM_SPECTOGRAMS, Melspectogram>::new();
         let mut steps: usize = 0;
+        let mut next_id = 0u64;
 
         loop {
             if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
@@ -89,14 +157,16 @@ impl Embedder {
                         return;
                     }
 
-                    // Don't compute the embeddings unless we have a full set of input (76 spectograms)
-                    // for the model, and we have strided the right number of steps
+                    // Don't compute the embeddings unless we have a full set of input
+                    // (76 spectograms) for the model, and we have strided the right number
+                    // of steps
                     if !spectograms.is_full() || !steps.is_multiple_of(step_interval) {
                         return;
                     }
 
-                    // Build a tensor that will be the input to the embedding model, which is [?, 76, 32, 1].
-                    // I presume that means [batch_size=1, num_melspectograms=76, num_spect_bins=32, ?].
+                    // Build a tensor that will be the input to the embedding model, which
+                    // is [?, 76, 32, 1]. I presume that means
+                    // [batch_size=1, num_melspectograms=76, num_spect_bins=32, ?].
                     let embedding_input = Tensor::from_shape(
                         &[1, NUM_SPECTOGRAMS, 32, 1],
                         spectograms
@@ -114,12 +184,17 @@ impl Embedder {
                         .run(tvec!(TValue::from(embedding_input)))
                         .unwrap()
                         .remove(0);
-                    let mut embedding = Embedding::default();
-                    embedding.0.clone_from_slice(out.as_slice::<f32>().unwrap());
+                    let mut embedding =
+                        Embedding::new([0f32; 96], next_id, spectograms.back().unwrap().sample());
+                    embedding
+                        .values
+                        .clone_from_slice(out.as_slice::<f32>().unwrap());
+                    next_id += 1;
 
                     if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
                         return;
                     }
+                    taps.send(&embedding);
                     if let Err(e) = tx.send(embedding) {
                         println!("failed send, embedding thread shutting down! {:?}", e);
                     }
@@ -0,0 +1,371 @@
+use crate::IntentConfig;
+use crate::{Action, ActionContext, ActionHandler, MatchEvent, Transcript};
+use regex::Regex;
+use std::collections::BTreeMap;
+
+/// A transcript which matched an intent, along with the values of any slots.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IntentMatch {
+    pub intent: String,
+    pub slots: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug)]
+struct Intent {
+    patterns: Vec<Regex>,
+    action: Action,
+}
+
+/// IntentMatcher maps transcripts to actions using sentence templates and regexes.
+pub struct IntentMatcher {
+    intents: BTreeMap<String, Intent>,
+    handler: Option<Box<dyn ActionHandler>>,
+}
+
+impl std::fmt::Debug for IntentMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IntentMatcher")
+            .field("intents", &self.intents)
+            .field("handler", &self.handler.is_some())
+            .finish()
+    }
+}
+
+impl Default for IntentMatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lowercases text, strips punctuation and collapses whitespace, so that transcripts
+/// like "Turn on the Living Room." match the template "turn on [the] {room}".
+pub fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '\'' {
+                c
+            } else {
+                ' '
+            }
+        })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Compiles a sentence template into a regex which matches an entire normalized transcript.
+///
+/// Templates are made of words, `[optional words]`, `(alternative|words)` and `{slot}`
+/// references. Slots with values listed in slots only match those values, other slots
+/// match any text.
+pub(crate) fn compile_template(
+    template: &str,
+    slots: &BTreeMap<String, Vec<String>>,
+) -> Result<Regex, anyhow::Error> {
+    let template = template.to_lowercase();
+    let mut out = String::from("^");
+    let mut chars = template.chars().peekable();
+    // For each open optional group, whether it took ownership of the preceding space.
+    let mut optionals: Vec<bool> = Vec::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            // Move the space surrounding optional words into the optional group, so the
+            // sentence still matches when the optional words are omitted.
+            '[' if out.ends_with(' ') => {
+                out.pop();
+                out.push_str("(?: ");
+                optionals.push(true);
+            }
+            '[' => {
+                out.push_str("(?:");
+                optionals.push(false);
+            }
+            ']' => {
+                if optionals.pop() == Some(false) && chars.peek() == Some(&' ') {
+                    chars.next();
+                    out.push_str(" )?");
+                } else {
+                    out.push_str(")?");
+                }
+            }
+            '(' => out.push_str("(?:"),
+            ')' | '|' => out.push(c),
+            '{' => {
+                let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                match slots.get(&name) {
+                    Some(values) => {
+                        let values: Vec<_> = values
+                            .iter()
+                            .map(|v| regex::escape(&normalize(v)))
+                            .collect();
+                        out.push_str(&format!("(?P<{}>{})", name, values.join("|")));
+                    }
+                    None => out.push_str(&format!("(?P<{}>.+?)", name)),
+                }
+            }
+            c if c.is_whitespace() && !out.ends_with(' ') => out.push(' '),
+            c if c.is_alphanumeric() || c == '\'' => out.push_str(&regex::escape(&c.to_string())),
+            _ => {}
+        }
+    }
+    out.push('$');
+    Ok(Regex::new(&out)?)
+}
+
+impl IntentMatcher {
+    pub fn new() -> Self {
+        let intents = BTreeMap::new();
+
+        Self {
+            intents,
+            handler: None,
+        }
+    }
+
+    /// Sets the handler which receives the actions of matched intents. Without a
+    /// handler, actions are run with [Action::run].
+    pub fn set_handler<H: ActionHandler + 'static>(&mut self, handler: H) {
+        self.handler = Some(Box::new(handler));
+    }
+
+    pub fn add_intent(&mut self, name: String, intent: IntentConfig) -> Result<(), anyhow::Error> {
+        let mut patterns = Vec::with_capacity(intent.sentences.len() + intent.regexes.len());
+        for sentence in intent.sentences.iter() {
+            patterns.push(compile_template(sentence, &intent.slots)?);
+        }
+        for re in intent.regexes.iter() {
+            patterns.push(Regex::new(re)?);
+        }
+
+        self.intents.insert(
+            name,
+            Intent {
+                patterns,
+                action: Action::try_from(&intent.action)?,
+            },
+        );
+        Ok(())
+    }
+
+    /// Returns the first intent which matches the transcript, if any.
+    pub fn recognize(&self, transcript: &str) -> Option<IntentMatch> {
+        let text = normalize(transcript);
+
+        for (name, intent) in self.intents.iter() {
+            for re in intent.patterns.iter() {
+                if let Some(caps) = re.captures(&text) {
+                    let slots = re
+                        .capture_names()
+                        .flatten()
+                        .filter_map(|n| {
+                            caps.name(n)
+                                .map(|m| (n.to_string(), m.as_str().to_string()))
+                        })
+                        .collect();
+                    return Some(IntentMatch {
+                        intent: name.clone(),
+                        slots,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Recognizes the transcript and runs the action of the matching intent. Slots are
+    /// available as template variables, and are passed to commands as trailing
+    /// `name=value` arguments.
+    pub fn eval(&mut self, transcript: &str) -> Option<IntentMatch> {
+        let m = self.recognize(transcript)?;
+        let mut ctx = ActionContext::new(&m.intent);
+        ctx.vars = m.slots.clone();
+        ctx.vars
+            .insert("transcript".to_string(), transcript.to_string());
+        ctx.args = m
+            .slots
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        let action = &self.intents[&m.intent].action;
+        match self.handler.as_mut() {
+            Some(handler) => handler.handle(action, &ctx),
+            None => action.run(&ctx),
+        }
+        Some(m)
+    }
+
+    /// Evaluates a transcript as [IntentMatcher::eval] does, returning it as an event so
+    /// it can be handled alongside those of a [crate::Matcher].
+    pub fn eval_transcript(&mut self, transcript: &Transcript) -> MatchEvent {
+        MatchEvent::Transcribed {
+            path: transcript.path.clone(),
+            text: transcript.text.clone(),
+            intent: self.eval(&transcript.text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn transcripts_become_events() {
+        let (send, recv) = channel();
+        let mut intents = IntentMatcher::new();
+        intents.set_handler(send);
+        intents
+            .add_intent(
+                "lights".to_string(),
+                IntentConfig {
+                    sentences: vec!["turn on [the] {room} lights".to_string()],
+                    regexes: vec![],
+                    slots: BTreeMap::new(),
+                    action: "exit:0".into(),
+                },
+            )
+            .unwrap();
+
+        let transcript = Transcript {
+            path: "utterance.wav".into(),
+            text: "Turn on the kitchen lights.".to_string(),
+        };
+        let MatchEvent::Transcribed { path, text, intent } = intents.eval_transcript(&transcript)
+        else {
+            panic!("expected a transcribed event");
+        };
+        assert_eq!(path, transcript.path);
+        assert_eq!(text, transcript.text);
+        let intent = intent.unwrap();
+        assert_eq!(intent.intent, "lights");
+        assert_eq!(intent.slots["room"], "kitchen");
+
+        let (action, ctx) = recv.try_recv().unwrap();
+        assert_eq!(action.exit_code(), Some(0));
+        assert_eq!(ctx.vars["transcript"], transcript.text);
+
+        let other = Transcript {
+            path: "other.wav".into(),
+            text: "what time is it".to_string(),
+        };
+        assert!(matches!(
+            intents.eval_transcript(&other),
+            MatchEvent::Transcribed { intent: None, .. }
+        ));
+        assert!(recv.try_recv().is_err());
+    }
+
+    /// Matches transcripts against one sentence template, returning the slots.
+    fn matches(
+        template: &str,
+        slots: &[(&str, &[&str])],
+        transcript: &str,
+    ) -> Option<BTreeMap<String, String>> {
+        let slots = slots
+            .iter()
+            .map(|(name, values)| {
+                (
+                    name.to_string(),
+                    values.iter().map(|v| v.to_string()).collect(),
+                )
+            })
+            .collect();
+        let re = compile_template(template, &slots).unwrap();
+        let text = normalize(transcript);
+        let caps = re.captures(&text)?;
+        Some(
+            re.capture_names()
+                .flatten()
+                .filter_map(|n| {
+                    caps.name(n)
+                        .map(|m| (n.to_string(), m.as_str().to_string()))
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn templates_match_nested_alternatives() {
+        let template = "((turn|switch) on|enable) [the] (lights|lamp)";
+        for transcript in ["turn on the lights", "Switch on lamp", "enable the lamp"] {
+            assert!(
+                matches(template, &[], transcript).is_some(),
+                "{}",
+                transcript
+            );
+        }
+        for transcript in [
+            "turn the lights",
+            "enable on the lights",
+            "turn on the light",
+        ] {
+            assert!(
+                matches(template, &[], transcript).is_none(),
+                "{}",
+                transcript
+            );
+        }
+    }
+
+    #[test]
+    fn templates_match_optional_words_at_either_end() {
+        let template = "[please] turn on the lights [now please]";
+        for transcript in [
+            "turn on the lights",
+            "please turn on the lights",
+            "turn on the lights now please",
+            "Please, turn on the lights now please!",
+        ] {
+            assert!(
+                matches(template, &[], transcript).is_some(),
+                "{}",
+                transcript
+            );
+        }
+        for transcript in ["pleaseturn on the lights", "turn on the lights now"] {
+            assert!(
+                matches(template, &[], transcript).is_none(),
+                "{}",
+                transcript
+            );
+        }
+    }
+
+    #[test]
+    fn templates_match_slots() {
+        let rooms: &[&str] = &["kitchen", "Living Room"];
+        let template = "turn on [the] {room} lights for {duration}";
+
+        // Values may contain spaces, and are matched after normalizing.
+        let slots = matches(
+            template,
+            &[("room", rooms)],
+            "turn on the living room lights for 5 minutes",
+        )
+        .unwrap();
+        assert_eq!(slots["room"], "living room");
+        // Slots without values, like duration, match any text.
+        assert_eq!(slots["duration"], "5 minutes");
+
+        assert!(
+            matches(
+                template,
+                &[("room", rooms)],
+                "turn on the garage lights for 1 hour"
+            )
+            .is_none()
+        );
+        assert!(
+            matches(
+                template,
+                &[("room", rooms)],
+                "turn on the kitchen lights for"
+            )
+            .is_none()
+        );
+    }
+}
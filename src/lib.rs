@@ -8,25 +8,70 @@ mod rechunker;
 pub use rechunker::Rechunker;
 
 mod sampler;
-pub use sampler::Sampler;
+pub use sampler::{SAMPLE_RATE, Sampler};
 
 mod vad;
-pub use vad::VAD;
+pub use vad::{VAD, VadSegment};
 
 mod specter;
-pub use specter::{Melspectogram, SPECTOGRAM_SAMPLES, Specter};
+pub use specter::{Melspectogram, SPECTOGRAM_SAMPLES, SPECTOGRAMS_PER_CHUNK, Specter};
+
+mod source;
+pub use source::ModelSource;
+
+mod cache;
+pub use cache::ModelCache;
+
+mod clock;
+pub use clock::{Clock, StreamClock, WallClock};
 
 mod tee;
 pub use tee::Tee;
 
 mod embedder;
-pub use embedder::{Embedder, Embedding, NUM_SPECTOGRAMS};
+pub use embedder::{EMBEDDING_INPUT_SHAPE, Embedder, Embedding, NUM_SPECTOGRAMS};
 
 mod runner;
-pub use runner::{NUM_EMBEDDINGS, NamedModel, Runner};
+pub use runner::{Activations, ModelFilters, ModelInfo, NUM_EMBEDDINGS, NamedModel, Runner};
+
+mod verifier;
+pub use verifier::{Verifier, windows};
+
+mod template;
+pub use template::Template;
+
+mod clip;
+pub use clip::{embed_clip, embedding_end, read_wav};
+
+mod action;
+pub use action::{
+    Action, ActionConfig, ActionContext, ActionHandler, ActionSpec, ExecConfig, HttpConfig,
+    HttpMethod, StageActivation,
+};
 
 mod matcher;
-pub use matcher::Matcher;
+pub use matcher::{MatchEvent, Matcher};
+
+mod check;
+pub use check::ConfigError;
+
+mod reload;
+pub use reload::{ConfigWatcher, Reload};
+
+mod intent;
+pub use intent::{IntentMatch, IntentMatcher};
+
+mod utterance;
+pub use utterance::{
+    RecordedUtterance, SavedUtterance, UtteranceFormat, UtteranceMeta, UtteranceStore,
+    UtteranceWriter,
+};
+
+mod transcriber;
+pub use transcriber::{
+    CommandTranscriber, OpenAITranscriber, Transcriber, TranscriberConfig, Transcript,
+    Transcription, Utterance, WyomingTranscriber,
+};
 
 /// A fixed-size buffer of contiguous audio samples.
 #[derive(Debug, Clone, PartialEq)]
@@ -35,38 +80,211 @@ pub struct Chunk<const S: usize> {
     pub samples: [f32; S],
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ModelConfig {
     pub path: String,
     pub scale: Option<f32>,
+    /// Whether the model is run from startup. Disabled models can be enabled at runtime.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Groups of models which can be enabled or disabled together.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Only report activations of the model which the verifier agrees with.
+    #[serde(default)]
+    pub verifier: Option<VerifierConfig>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// A [Verifier] trained with the `verify` subcommand.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerifierConfig {
+    pub path: String,
+    /// The probability the verifier must give for an activation to be reported, defaults
+    /// to 0.5. Activations it rejects are reported as 0.
+    #[serde(default)]
+    pub threshold: Option<f32>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct MatchStageConfig {
-    pub model: String,
+    /// The model which must activate. Exactly one of model, any_of and all_of must be set.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Matches when any one of these models activates, such as one of several commands.
+    #[serde(default)]
+    pub any_of: Vec<String>,
+    /// Matches when all of these models activate within window_ms of each other.
+    #[serde(default)]
+    pub all_of: Vec<String>,
+    #[serde(default)]
+    pub window_ms: Option<usize>,
     pub activation_threshold: Option<f32>,
     pub timeout_ms: Option<usize>,
+    /// The stage may be skipped if a later stage activates first. A rule ending in
+    /// optional stages fires once they time out.
+    #[serde(default)]
+    pub optional: bool,
+    /// Models which must not have activated recently for this stage to match. If any
+    /// did, the rule is reset.
+    #[serde(default)]
+    pub unless: Vec<InhibitorConfig>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InhibitorConfig {
+    pub model: String,
+    pub activation_threshold: Option<f32>,
+    /// How long before the stage matches to look for an activation of the model.
+    pub window_ms: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct MatchConfig {
     pub chain: Vec<MatchStageConfig>,
-    pub action: String,
+    pub action: ActionConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IntentConfig {
+    /// Sentence templates such as `turn on [the] {room}`, supporting `[optional]` words,
+    /// `(alternative|words)` and `{slot}` references.
+    #[serde(default)]
+    pub sentences: Vec<String>,
+    /// Regexes matched against the lowercased, punctuation-free transcript. Named
+    /// capture groups become slots.
+    #[serde(default)]
+    pub regexes: Vec<String>,
+    /// Allowed values of each slot. Slots without values match any text.
+    #[serde(default)]
+    pub slots: BTreeMap<String, Vec<String>>,
+    pub action: ActionConfig,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Maximum number of utterances to keep.
+    pub max_files: Option<usize>,
+    /// Maximum size of all utterances (including sidecars) in bytes.
+    pub max_bytes: Option<u64>,
+    /// Maximum age of an utterance in seconds.
+    pub max_age_secs: Option<u64>,
+}
+
+impl RetentionConfig {
+    /// Whether any limit is set.
+    pub fn is_enabled(&self) -> bool {
+        self.max_files.is_some() || self.max_bytes.is_some() || self.max_age_secs.is_some()
+    }
 }
 
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct UtteranceConfig {
     #[serde(default)]
     pub wakeword: Option<String>,
+    /// Shorthand for an action running this command with the utterance path as the first argument.
     #[serde(default)]
     pub exec: Option<String>,
+    /// Action to run after an utterance is saved, `{utterance}` is the path of the audio.
+    #[serde(default)]
+    pub action: Option<ActionConfig>,
+    #[serde(default)]
+    pub format: UtteranceFormat,
+    /// Write a JSON file describing each utterance alongside the audio.
+    #[serde(default)]
+    pub sidecar: bool,
+    /// Directory utterances are written to, defaults to the system temporary directory.
+    #[serde(default)]
+    pub dir: Option<String>,
+    /// Template for the utterance filename, without extension. Supports `{model}` (the
+    /// wakeword which triggered the recording), `{seq}`, `{timestamp}` (YYYYMMDDhhmmss),
+    /// `{millis}` and `{unix_ms}`.
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// Limits on the utterances kept. Needs `dir` to be set, and only applies to
+    /// utterances saved while it is set.
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    /// Backend used to transcribe utterances after they are saved.
+    #[serde(default)]
+    pub transcriber: Option<TranscriberConfig>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunnerConfig {
+    /// Only run models which are used in later stages of a chain while some rule is
+    /// waiting for them, see [Runner::gate].
+    #[serde(default)]
+    pub gating: bool,
+    /// Number of threads models are run on, defaults to one per core, as does 0. With 1,
+    /// models are run one after another on the runner thread.
+    #[serde(default)]
+    pub threads: Option<usize>,
+    /// Merge models into a single execution graph, see [Runner].
+    #[serde(default)]
+    pub fused: bool,
+}
+
+/// Overrides for the models shared by all wakewords, see [ModelSource::melspectrogram]
+/// and [ModelSource::embedding].
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeaturesConfig {
+    #[serde(default)]
+    pub melspectrogram: Option<String>,
+    #[serde(default)]
+    pub embedding: Option<String>,
+}
+
+impl FeaturesConfig {
+    pub fn melspectrogram(&self) -> ModelSource<'static> {
+        match &self.melspectrogram {
+            Some(path) => path.as_str().into(),
+            None => ModelSource::melspectrogram(),
+        }
+    }
+
+    pub fn embedding(&self) -> ModelSource<'static> {
+        match &self.embedding {
+            Some(path) => path.as_str().into(),
+            None => ModelSource::embedding(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub models: BTreeMap<String, ModelConfig>,
     pub matchers: BTreeMap<String, MatchConfig>,
 
     #[serde(default)]
     pub utterance: UtteranceConfig,
+    #[serde(default)]
+    pub intents: BTreeMap<String, IntentConfig>,
+    #[serde(default)]
+    pub runner: RunnerConfig,
+    #[serde(default)]
+    pub features: FeaturesConfig,
+    /// Directory where compiled models are kept, see [ModelCache].
+    #[serde(default)]
+    pub model_cache: Option<String>,
+}
+
+impl Config {
+    pub fn model_cache(&self) -> Option<ModelCache> {
+        self.model_cache.as_ref().map(ModelCache::new)
+    }
 }
@@ -1,107 +1,637 @@
-use clap::Parser;
-use std::env::temp_dir;
-use std::fs::File;
-use std::io::BufReader;
+use clap::{Parser, Subcommand};
 use std::sync::Arc;
 use std::sync::atomic::AtomicU64;
-use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::{RecvTimeoutError, sync_channel};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
 use oww_rust_core::*;
 
+/// Number of samples in each chunk read from the microphone.
+const CHUNK_SAMPLES: usize = 640;
+/// Number of melspectograms between each embedding.
+const EMBEDDING_STEP: usize = 4;
+/// Number of samples of audio between each frame of model results.
+const SAMPLES_PER_FRAME: u64 = (SPECTOGRAM_SAMPLES / SPECTOGRAMS_PER_CHUNK * EMBEDDING_STEP) as u64;
+/// Number of samples without voice activity after which a recording ends.
+const SILENCE_SAMPLES: u64 = 2 * SAMPLE_RATE as u64;
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate a config file, loading every model and checking all references
+    Check {
+        /// yaml-formatted config file
+        config_file: String,
+    },
+    /// Compile every model in a config file into the model cache, for faster startup
+    Compile {
+        /// yaml-formatted config file
+        config_file: String,
+        /// cache directory, defaults to model_cache from the config file
+        #[arg(short, long)]
+        cache: Option<String>,
+    },
+    /// Train a verifier for a model on clips of its wake word and of other speech or noise
+    Verify(VerifyArgs),
+    /// Record a few examples of a phrase, and save them as a template it can be detected by
+    Enroll(EnrollArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct EnrollArgs {
+    /// yaml-formatted config file
+    config_file: String,
+    /// mono 16kHz WAV files of the phrase, which is recorded from the microphone if none
+    /// are given
+    clips: Vec<String>,
+    /// where to write the template, which must end in `.template`
+    #[arg(short, long)]
+    output: String,
+    /// number of examples to record
+    #[arg(short = 'n', long, default_value_t = 3)]
+    count: usize,
+    /// seconds of audio recorded for each example
+    #[arg(short, long, default_value_t = 2.)]
+    seconds: f32,
+    /// distance at which the phrase is detected, worked out from the examples by default
+    #[arg(short, long)]
+    threshold: Option<f32>,
     /// scale samples by this amount
     #[arg(short, long)]
     preamp: Option<f32>,
-    /// input microphone to listen on
+    /// input microphone to record from
     #[arg(short, long)]
     device: Option<String>,
+}
 
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
     /// yaml-formatted config file
     config_file: String,
+    /// name of the model to verify
+    model: String,
+    /// mono 16kHz WAV files of the wake word, spoken by the people who will use it
+    #[arg(long, required = true, num_args = 1..)]
+    positive: Vec<String>,
+    /// mono 16kHz WAV files of other speech and background noise
+    #[arg(long, required = true, num_args = 1..)]
+    negative: Vec<String>,
+    /// where to write the verifier
+    #[arg(short, long)]
+    output: String,
+    /// windows of positive clips the model scores at least this are trained on
+    #[arg(short, long, default_value_t = 0.5)]
+    threshold: f32,
+    /// fraction of the clips of each kind held out of training to measure accuracy on,
+    /// after which the verifier is trained on every clip
+    #[arg(long, default_value_t = 0.2)]
+    holdout: f32,
+    /// scale samples by this amount, as when listening
+    #[arg(long)]
+    preamp: Option<f32>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    /// scale samples by this amount
+    #[arg(short, long)]
+    preamp: Option<f32>,
+    /// input microphone to listen on
+    #[arg(short, long)]
+    device: Option<String>,
+    /// log matcher progress (-v), and model activations every frame (-vv)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// yaml-formatted config file
+    config_file: Option<String>,
 }
 
 fn main() {
     let args = Args::parse();
-    let reader = BufReader::new(File::open(args.config_file).expect("failed opening config file"));
-    let config: Config = serde_yaml::from_reader(reader).unwrap();
+    match args.command {
+        Some(Command::Check { config_file }) => check(&config_file),
+        Some(Command::Compile { config_file, cache }) => compile(&config_file, cache),
+        Some(Command::Verify(args)) => verify(args),
+        Some(Command::Enroll(args)) => enroll(args),
+        None => match args.run.config_file.clone() {
+            Some(config_file) => run(args.run, &config_file),
+            None => {
+                eprintln!("a config file is required, see --help");
+                std::process::exit(2);
+            }
+        },
+    }
+}
+
+/// Loads the config file, exiting non-zero if it can't be read or parsed.
+fn load_config(config_file: &str) -> Config {
+    match Config::load(config_file) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}: {}", config_file, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Validates the config file, printing each problem and exiting non-zero if any were found.
+fn check(config_file: &str) {
+    let config = load_config(config_file);
+
+    let errs = config.validate(true);
+    for e in errs.iter() {
+        println!("{}: {}", config_file, e);
+    }
+    if !errs.is_empty() {
+        println!("{} problem(s) found", errs.len());
+        std::process::exit(1);
+    }
+    println!("{}: ok", config_file);
+}
+
+/// Compiles the feature models and every model in the config file into the model cache,
+/// replacing existing entries.
+fn compile(config_file: &str, cache_dir: Option<String>) {
+    let config = load_config(config_file);
+    let Some(cache) = cache_dir
+        .map(ModelCache::new)
+        .or_else(|| config.model_cache())
+    else {
+        println!("{}: model_cache is not set, pass --cache", config_file);
+        std::process::exit(1);
+    };
+
+    let mut sources = vec![
+        (
+            "features.melspectrogram".to_string(),
+            config.features.melspectrogram(),
+            None,
+        ),
+        (
+            "features.embedding".to_string(),
+            config.features.embedding(),
+            Some(&EMBEDDING_INPUT_SHAPE[..]),
+        ),
+    ];
+    for (name, m) in config.models.iter() {
+        if Template::is_template(&m.path) {
+            continue;
+        }
+        sources.push((format!("models.{}", name), m.path.as_str().into(), None));
+    }
+
+    let mut failed = 0;
+    for (name, source, input_shape) in sources {
+        let start = Instant::now();
+        match cache.compile(source, input_shape) {
+            Ok(path) => println!("{}: {:?} ({:.1?})", name, path, start.elapsed()),
+            Err(e) => {
+                println!("{}: {:#}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        println!("{} model(s) failed to compile", failed);
+        std::process::exit(1);
+    }
+}
+
+/// Trains a verifier for a model, on the windows of positive clips the model activates on
+/// and every window of negative clips.
+/// Accuracy is reported on some clips held out of training, before the saved verifier is
+/// trained on all of them.
+fn verify(args: VerifyArgs) {
+    let config = load_config(&args.config_file);
+    let Some(params) = config.models.get(&args.model) else {
+        println!("{}: unknown model {:?}", args.config_file, args.model);
+        std::process::exit(1);
+    };
+    let model = NamedModel::new(
+        args.model.as_str(),
+        params.path.as_str(),
+        params.scale.unwrap_or(1.),
+    )
+    .unwrap_or_else(|e| {
+        println!("{}: failed loading: {:#}", params.path, e);
+        std::process::exit(1);
+    });
+
+    let preamp = args.preamp.unwrap_or(0.1);
+    let embed = |path: &str| {
+        let embeddings = read_wav(path, preamp).and_then(|samples| {
+            embed_clip(
+                &samples,
+                config.features.melspectrogram(),
+                config.features.embedding(),
+                EMBEDDING_STEP,
+            )
+        });
+        embeddings.unwrap_or_else(|e| {
+            println!("{}: {:#}", path, e);
+            std::process::exit(1);
+        })
+    };
+
+    // Windows are kept by clip, as those of one clip are too alike to test each other.
+    let mut positive = Vec::new();
+    for path in args.positive.iter() {
+        let embeddings = embed(path);
+        let scores = model.scores(&embeddings);
+        let mut clip: Vec<(f32, Vec<f32>)> = scores
+            .into_iter()
+            .zip(windows(&embeddings, model.window()))
+            .collect();
+        let best = clip.iter().map(|(score, _)| *score).fold(0., f32::max);
+        if best < args.threshold {
+            // Train on the window closest to activating, rather than dropping the clip.
+            println!(
+                "{}: model never reaches {} (best {:.3}), using its best window",
+                path, args.threshold, best
+            );
+            clip.retain(|(score, _)| *score == best);
+            clip.truncate(1);
+        } else {
+            clip.retain(|(score, _)| *score >= args.threshold);
+        }
+        positive.push(
+            clip.into_iter()
+                .map(|(_, window)| window)
+                .collect::<Vec<_>>(),
+        );
+    }
+    let mut negative = Vec::new();
+    for path in args.negative.iter() {
+        negative.push(windows(&embed(path), model.window()).collect::<Vec<_>>());
+    }
+
+    let train = |positive: &[Vec<Vec<f32>>], negative: &[Vec<Vec<f32>>]| {
+        Verifier::train(&positive.concat(), &negative.concat(), model.window()).unwrap_or_else(
+            |e| {
+                println!("failed training verifier: {:#}", e);
+                std::process::exit(1);
+            },
+        )
+    };
+    let (train_positive, test_positive) = holdout(&positive, args.holdout);
+    let (train_negative, test_negative) = holdout(&negative, args.holdout);
+    let mut verifier = train(&train_positive, &train_negative);
+    report_accuracy("training", &verifier, &train_positive, &train_negative);
+    if test_positive.is_empty() && test_negative.is_empty() {
+        println!("no clips held out, pass more clips to measure accuracy on unseen audio");
+    } else {
+        report_accuracy("held out", &verifier, &test_positive, &test_negative);
+        println!("training on every clip");
+        verifier = train(&positive, &negative);
+    }
+
+    if let Err(e) = verifier.save(&args.output) {
+        println!("{:#}", e);
+        std::process::exit(1);
+    }
+    println!(
+        "wrote {}, set it as models.{}.verifier.path to use it",
+        args.output, args.model
+    );
+}
+
+/// Splits clips into those to train on and those to test on, holding out about fraction of
+/// them spread through the list. At least one clip is always kept for training.
+fn holdout<T: Clone>(clips: &[T], fraction: f32) -> (Vec<T>, Vec<T>) {
+    let n = clips.len();
+    let held = ((n as f32 * fraction).round() as usize).min(n.saturating_sub(1));
+    let mut train = Vec::new();
+    let mut test = Vec::new();
+    for (i, clip) in clips.iter().enumerate() {
+        match (i + 1) * held / n > i * held / n {
+            true => test.push(clip.clone()),
+            false => train.push(clip.clone()),
+        }
+    }
+    (train, test)
+}
+
+/// Prints how many windows of the positive and negative clips the verifier gets right.
+fn report_accuracy(
+    label: &str,
+    verifier: &Verifier,
+    positive: &[Vec<Vec<f32>>],
+    negative: &[Vec<Vec<f32>>],
+) {
+    let count = |clips: &[Vec<Vec<f32>>], accept: bool| {
+        let windows = clips.iter().flatten();
+        let right = windows
+            .clone()
+            .filter(|w| (verifier.probability(w) >= 0.5) == accept)
+            .count();
+        format!(
+            "{}/{} windows of {} clip(s)",
+            right,
+            windows.count(),
+            clips.len()
+        )
+    };
+    println!(
+        "{} accuracy: accepts {} positive, rejects {} negative",
+        label,
+        count(positive, true),
+        count(negative, false)
+    );
+}
+
+/// Records examples of a phrase from the microphone, prompting before each one.
+fn record(args: &EnrollArgs) -> Vec<(String, Vec<f32>)> {
+    let mut sampler = Sampler::<CHUNK_SAMPLES>::start(args.preamp, args.device.clone())
+        .expect("failed to start listening for samples");
+    let chunks = sampler.take_receiver().unwrap();
+    let len = (args.seconds * SAMPLE_RATE as f32) as usize;
+
+    let mut out = Vec::with_capacity(args.count);
+    for i in 0..args.count {
+        println!("example {}/{}: get ready...", i + 1, args.count);
+        thread::sleep(Duration::from_secs(1));
+        chunks.try_iter().for_each(drop);
+        println!("example {}/{}: say the phrase now", i + 1, args.count);
+
+        let mut samples = Vec::with_capacity(len);
+        while samples.len() < len {
+            samples.extend_from_slice(&chunks.recv().unwrap().samples);
+        }
+        out.push((format!("example {}", i + 1), samples));
+    }
+    out
+}
+
+/// Enrolls a phrase from recorded examples. Each example is trimmed to the embeddings
+/// covering the speech in it, found by voice activity detection. Unless it is given, the
+/// threshold is the furthest any example is from the others, plus a margin.
+fn enroll(args: EnrollArgs) {
+    if !Template::is_template(&args.output) {
+        println!(
+            "{}: templates must be saved with a .template extension",
+            args.output
+        );
+        std::process::exit(1);
+    }
+    let config = load_config(&args.config_file);
+    let preamp = args.preamp.unwrap_or(0.1);
+    let clips = match args.clips.is_empty() {
+        true => record(&args),
+        false => args
+            .clips
+            .iter()
+            .map(|path| {
+                let samples = read_wav(path, preamp).unwrap_or_else(|e| {
+                    println!("{:#}", e);
+                    std::process::exit(1);
+                });
+                (path.clone(), samples)
+            })
+            .collect(),
+    };
+
+    // Lead each example with silence, so a full window of embeddings ends as it does.
+    let lead = NUM_EMBEDDINGS * EMBEDDING_STEP * SPECTOGRAM_SAMPLES / SPECTOGRAMS_PER_CHUNK;
+    let mut examples = Vec::new();
+    let mut windows = Vec::new();
+    for (name, samples) in clips {
+        let segments = VAD::segments(&samples).unwrap_or_default();
+        let (Some(first), Some(last)) = (segments.first(), segments.last()) else {
+            println!("{}: no speech found, skipping", name);
+            continue;
+        };
+        let start = lead + first.start_ms as usize * SAMPLE_RATE / 1000;
+        let end = lead + last.end_ms as usize * SAMPLE_RATE / 1000;
+
+        let padded: Vec<f32> = std::iter::repeat_n(0., lead).chain(samples).collect();
+        let embeddings = embed_clip(
+            &padded,
+            config.features.melspectrogram(),
+            config.features.embedding(),
+            EMBEDDING_STEP,
+        )
+        .unwrap_or_else(|e| {
+            println!("{}: {:#}", name, e);
+            std::process::exit(1);
+        });
+        let covers = |at: usize| {
+            (0..embeddings.len())
+                .find(|i| embedding_end(*i, EMBEDDING_STEP) >= at)
+                .unwrap_or(embeddings.len() - 1)
+        };
+        let (from, to) = (covers(start), covers(end));
+        println!(
+            "{}: {}ms of speech, {} embeddings",
+            name,
+            last.end_ms - first.start_ms,
+            to + 1 - from
+        );
+        examples.push(embeddings[from..=to].to_vec());
+        windows.push(embeddings[to.saturating_sub(NUM_EMBEDDINGS - 1)..=to].to_vec());
+    }
+    if examples.len() < 2 {
+        println!("at least 2 examples with speech are needed");
+        std::process::exit(1);
+    }
+
+    // How far each example is from the closest of the others, as it would be scored live.
+    let mut furthest = 0f32;
+    for (i, window) in windows.iter().enumerate() {
+        let mut others = examples.clone();
+        others.remove(i);
+        let distance = Template::new(&others, 1.)
+            .distances(window)
+            .into_iter()
+            .fold(f32::INFINITY, f32::min);
+        println!("example {}: distance {:.3} to the others", i + 1, distance);
+        furthest = furthest.max(distance);
+    }
+    let threshold = args.threshold.unwrap_or(furthest * 1.25);
+    println!("threshold {:.3}", threshold);
+
+    if let Err(e) = Template::new(&examples, threshold).save(&args.output) {
+        println!("{:#}", e);
+        std::process::exit(1);
+    }
+    println!(
+        "wrote {}, add it to models with `path: {}` to detect the phrase",
+        args.output, args.output
+    );
+}
+
+fn run(args: RunArgs, config_file: &str) {
+    let mut config = Config::load(config_file).expect("failed loading config file");
 
     // Sample from microphone in 640-sample chunks, split into two streams
-    let mut sampler = Sampler::<640>::start(args.preamp, args.device)
+    let mut sampler = Sampler::<CHUNK_SAMPLES>::start(args.preamp, args.device)
         .expect("failed to start listening for samples");
-    let mut tee = Tee::<640, 3>::start(sampler.take_receiver().unwrap()).unwrap();
+    let mut tee = Tee::<CHUNK_SAMPLES, 3>::start(sampler.take_receiver().unwrap()).unwrap();
 
-    // VAD pipeline: rechunk to 480-sample chunks, run through VAD, record timestamp of last activity
-    let mut vad_rechunker = Rechunker::<640, 480>::start(tee.take_receiver(0).unwrap()).unwrap();
+    // VAD pipeline: rechunk to 480-sample chunks, run through VAD, record the sample
+    // position at the end of the last activity
+    let mut vad_rechunker =
+        Rechunker::<CHUNK_SAMPLES, 480>::start(tee.take_receiver(0).unwrap()).unwrap();
     let mut vad = VAD::start(vad_rechunker.take_receiver().unwrap()).unwrap();
     let vad_recv = vad.take_receiver().unwrap();
-    let last_activity = Arc::new(AtomicU64::new(500));
+    let last_activity = Arc::new(AtomicU64::new(0));
     let la = last_activity.clone();
     thread::spawn(move || {
         loop {
-            if vad_recv.recv().unwrap() {
-                la.store(
-                    SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs(),
-                    std::sync::atomic::Ordering::SeqCst,
-                );
+            let (id, active) = vad_recv.recv().unwrap();
+            if active {
+                la.store((id + 1) * 480, std::sync::atomic::Ordering::SeqCst);
             }
         }
     });
 
     // Record pipeline
     let mut record_rechunker =
-        Rechunker::<640, 4000>::start(tee.take_receiver(1).unwrap()).unwrap();
+        Rechunker::<CHUNK_SAMPLES, 4000>::start(tee.take_receiver(1).unwrap()).unwrap();
     let mut record_delay =
         Delay::<4000, 4>::start(record_rechunker.take_receiver().unwrap()).unwrap();
 
     // Wakeword pipeline
     let mut specter_rechunker =
-        Rechunker::<640, SPECTOGRAM_SAMPLES>::start(tee.take_receiver(2).unwrap()).unwrap();
-    let mut specter = Specter::start(specter_rechunker.take_receiver().unwrap())
-        .expect("failed to start processing samples");
-    let mut embedder = Embedder::start(specter.take_receiver().unwrap(), 4)
-        .expect("failed to start processing spectos");
+        Rechunker::<CHUNK_SAMPLES, SPECTOGRAM_SAMPLES>::start(tee.take_receiver(2).unwrap())
+            .unwrap();
+    let cache = config.model_cache();
+    let mut melspectrogram = config.features.melspectrogram();
+    let mut embedding = config.features.embedding();
+    if let Some(cache) = &cache {
+        melspectrogram = cache
+            .get(melspectrogram, None)
+            .expect("failed compiling melspectrogram model");
+        embedding = cache
+            .get(embedding, Some(&EMBEDDING_INPUT_SHAPE))
+            .expect("failed compiling embedding model");
+    }
+    let mut specter =
+        Specter::start_with_model(specter_rechunker.take_receiver().unwrap(), melspectrogram)
+            .expect("failed to start processing samples");
+    let mut embedder =
+        Embedder::start_with_model(specter.take_receiver().unwrap(), EMBEDDING_STEP, embedding)
+            .expect("failed to start processing spectos");
 
-    let mut runner = Runner::start(embedder.take_receiver().unwrap())
+    let mut runner = Runner::start_with_config(embedder.take_receiver().unwrap(), &config.runner)
         .expect("failed to start processing embeddings");
 
-    for (name, params) in config.models {
-        let m = NamedModel::new(name, params.path, params.scale.unwrap_or(1.)).unwrap();
-        runner.add_model(m);
+    for (name, params) in config.models.iter() {
+        runner.add_model(NamedModel::from_config(name, params, cache.as_ref()).unwrap());
     }
-
-    let mut matcher = Matcher::new();
-    for (name, params) in config.matchers {
-        matcher.add_rule(name, params);
+    if args.verbose >= 1 {
+        for m in runner.models() {
+            println!(
+                "model {}: {} (scale {}, {}, groups {:?})",
+                m.name,
+                m.path,
+                m.filters.scale,
+                if m.enabled { "enabled" } else { "disabled" },
+                m.groups
+            );
+        }
     }
 
+    // Matcher timeouts follow the audio stream, advancing by one frame per set of results.
+    let clock = StreamClock::new();
+    let mut matcher = build_matcher(&config, &clock).expect("failed to add matcher rule");
+
+    // Action run when an utterance is saved, `exec` is shorthand for passing the path as the
+    // first arg.
+    let utterance_action = match (&config.utterance.action, &config.utterance.exec) {
+        (Some(action), _) => Some(Action::try_from(action).expect("invalid utterance action")),
+        (None, Some(cmd)) => {
+            let mut spl = shlex::Shlex::new(cmd);
+            Some(Action::Exec(ExecConfig {
+                command: spl.next().expect("empty utterance exec command"),
+                args: ["{utterance}".to_string()].into_iter().chain(spl).collect(),
+                env: Default::default(),
+                cwd: None,
+                timeout_ms: None,
+                stdin: None,
+            }))
+        }
+        (None, None) => None,
+    };
+
+    // Utterances are written in the background, so encoding them doesn't delay detection.
+    let store =
+        UtteranceStore::new(&config.utterance).expect("failed to create utterance directory");
+    let (recorded_send, recorded_recv) = sync_channel::<RecordedUtterance>(4);
+    let mut writer =
+        UtteranceWriter::start(store, recorded_recv).expect("failed to start utterance writer");
+    let saved = writer.take_receiver().unwrap();
+
+    let mut intents = build_intents(&config).expect("failed to compile intent");
+    runner.gate(&matcher, config.utterance.wakeword.as_slice());
+
+    // Reload models, matchers and intents when the config changes.
+    let mut watcher =
+        ConfigWatcher::start(config_file, &config).expect("failed to watch config file");
+    let reloads = watcher.take_receiver().unwrap();
+
+    // Transcription pipeline, if configured
+    let (utterance_send, utterance_recv) = sync_channel::<Utterance>(4);
+    let mut transcription = config.utterance.transcriber.as_ref().map(|t| {
+        Transcription::start(
+            utterance_recv,
+            t.build().expect("failed to create transcriber"),
+        )
+        .unwrap()
+    });
+    let transcripts = transcription.as_mut().and_then(|t| t.take_receiver());
+
     let rec = record_delay.take_receiver().unwrap();
     let recv = runner.take_receiver().unwrap();
-    let mut recording: Option<Vec<f32>> = None;
+    let mut recording: Option<Recording> = None;
     loop {
         match recv.recv_timeout(Duration::from_millis(1)) {
             Ok(results) => {
-                // Not recording, lets see if recording is triggered.
-                if let Some(wakeword) = &config.utterance.wakeword
-                    && recording.is_none()
-                {
-                    if let Some((_, score)) = results.iter().find(|(name, _score)| name == wakeword)
-                        && *score > 0.6
-                    {
-                        // Recording has been triggered
-                        recording = Some(Vec::with_capacity(16_000 * 32));
-                    } else {
+                if let Some(wakeword) = &config.utterance.wakeword {
+                    let score = results
+                        .iter()
+                        .find(|(name, _score)| **name == **wakeword)
+                        .map(|(_, score)| *score);
+
+                    match (&mut recording, score) {
+                        // Recording is in progress, track the peak activation.
+                        (Some(r), Some(score)) => r.peak_score = r.peak_score.max(score),
+                        // Not recording, and recording has been triggered.
+                        (None, Some(score)) if score > 0.6 => {
+                            recording = Some(Recording {
+                                samples: Vec::with_capacity(SAMPLE_RATE * 32),
+                                trigger_model: wakeword.clone(),
+                                peak_score: score,
+                                started_at: chrono::Local::now(),
+                                position: 0,
+                            });
+                        }
                         // Drop recording samples.
-                        rec.try_iter().for_each(|_| ());
+                        (None, _) => rec.try_iter().for_each(|_| ()),
+                        _ => {}
+                    }
+                }
+                if args.verbose >= 2 {
+                    println!("{:?}", results);
+                }
+                clock.advance(SAMPLES_PER_FRAME);
+                for event in matcher.eval(&results) {
+                    if args.verbose >= 1 || matches!(event, MatchEvent::RuleFired { .. }) {
+                        println!("{}", event);
                     }
                 }
-                matcher.eval(results);
+                runner.recycle(results);
+                runner.update_gate(&matcher);
             }
             Err(e) => {
                 if e == RecvTimeoutError::Disconnected {
@@ -110,62 +640,179 @@ fn main() {
             }
         }
 
-        match &mut recording {
-            Some(r) => {
-                // Recording is in progress, lets:
-                //  - Add fresh samples from the recording pipe
-                //  - See if VAD has been inactive for long enough to terminate
-                if last_activity.fetch_add(0, std::sync::atomic::Ordering::SeqCst)
-                    < SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs()
-                        - 2
-                {
-                    let fname = format!(
-                        "{}/utterance_{}.wav",
-                        temp_dir().as_os_str().to_str().unwrap(),
-                        current_time_string()
-                    );
-                    wavers::write(&fname, r, 16_000, 1).unwrap();
-                    recording = None;
-
-                    // Run the utterance command if any.
-                    if let Some(cmd) = &config.utterance.exec {
-                        let mut cmd = shlex::Shlex::new(cmd);
-                        use std::process::Command;
-
-                        let mut c = Command::new(cmd.next().unwrap());
-                        let cmd = c
-                            .current_dir(std::env::current_dir().unwrap())
-                            .args([fname].into_iter().chain(cmd));
-                        println!("spawning: {:?}", &cmd);
-                        println!("result: {:?}", cmd.spawn());
-                    }
+        if let Ok(reload) = reloads.try_recv() {
+            let applied = apply_reload(
+                reload,
+                &mut config,
+                &mut runner,
+                &mut matcher,
+                &mut intents,
+                &clock,
+            );
+            if let Err(e) = &applied {
+                println!("failed applying reloaded config, keeping previous: {:?}", e);
+            }
+            watcher.applied(applied.is_ok());
+        }
 
-                    // Drop recording samples.
-                    rec.try_iter().for_each(|_| ());
-                } else {
-                    while let Ok(chunk) = rec.try_recv() {
-                        r.extend_from_slice(&chunk.samples);
-                    }
+        while let Ok(saved) = saved.try_recv() {
+            // Run the utterance action if any.
+            if let Some(action) = &utterance_action {
+                let model = &saved.utterance.trigger_model;
+                let mut ctx = ActionContext::new(model);
+                ctx.model = Some(model.clone());
+                ctx.score = Some(saved.utterance.peak_score);
+                ctx.utterance = Some(saved.path.clone());
+                handle_action(action, &ctx);
+            }
+
+            if transcripts.is_some() {
+                let utterance = Utterance {
+                    path: saved.path,
+                    samples: saved.utterance.samples,
+                };
+                if let Err(e) = utterance_send.try_send(utterance) {
+                    println!("dropping utterance for transcription: {:?}", e);
+                }
+            }
+        }
+
+        if let Some(transcripts) = &transcripts {
+            while let Ok(t) = transcripts.try_recv() {
+                println!("{}", intents.eval_transcript(&t));
+            }
+        }
+
+        if let Some(r) = &mut recording {
+            // Recording is in progress, lets:
+            //  - Add fresh samples from the recording pipe
+            //  - See if VAD has been inactive for long enough to terminate
+            while let Ok(chunk) = rec.try_recv() {
+                r.samples.extend_from_slice(&chunk.samples);
+                r.position = (chunk.id + 1) * 4000;
+            }
+            if r.position
+                > last_activity.load(std::sync::atomic::Ordering::SeqCst) + SILENCE_SAMPLES
+            {
+                let r = recording.take().unwrap();
+                let utterance = RecordedUtterance {
+                    samples: r.samples,
+                    trigger_model: r.trigger_model,
+                    peak_score: r.peak_score,
+                    started_at: r.started_at,
+                    ended_at: chrono::Local::now(),
+                };
+                if let Err(e) = recorded_send.try_send(utterance) {
+                    println!("dropping utterance, writer is busy: {:?}", e);
                 }
+
+                // Drop recording samples.
+                rec.try_iter().for_each(|_| ());
             }
-            None => {}
         }
     }
 }
 
-fn current_time_string() -> String {
-    use chrono::{Datelike, Timelike};
-    let now = chrono::Local::now();
-    format!(
-        "{:04}{:02}{:02}{:02}{:02}{:02}",
-        now.year(),
-        now.month(),
-        now.day(),
-        now.hour(),
-        now.minute(),
-        now.second()
-    )
+fn build_matcher(config: &Config, clock: &StreamClock) -> Result<Matcher, anyhow::Error> {
+    let mut matcher = Matcher::with_clock(clock.clone());
+    matcher.set_handler(handle_action);
+    for (name, params) in config.matchers.iter() {
+        matcher.add_rule(name.clone(), params.clone())?;
+    }
+    Ok(matcher)
+}
+
+fn build_intents(config: &Config) -> Result<IntentMatcher, anyhow::Error> {
+    let mut intents = IntentMatcher::new();
+    intents.set_handler(handle_action);
+    for (name, params) in config.intents.iter() {
+        intents.add_intent(name.clone(), params.clone())?;
+    }
+    Ok(intents)
+}
+
+/// Swaps in a reloaded config. Rules in progress are reset, while audio capture and the
+/// warmed-up feature pipeline carry on. Utterance and runner settings only apply after a
+/// restart.
+/// Nothing is changed if the reloaded rules fail to build.
+fn apply_reload(
+    reload: Reload,
+    config: &mut Config,
+    runner: &mut Runner,
+    matcher: &mut Matcher,
+    intents: &mut IntentMatcher,
+    clock: &StreamClock,
+) -> Result<(), anyhow::Error> {
+    let new_matcher = build_matcher(&reload.config, clock)?;
+    let new_intents = build_intents(&reload.config)?;
+
+    for name in reload.removed.iter() {
+        runner.remove_model(name);
+    }
+    let changed = reload.models.len();
+    for m in reload.models {
+        runner.add_model(m);
+    }
+    *matcher = new_matcher;
+    *intents = new_intents;
+    runner.gate(matcher, config.utterance.wakeword.as_slice());
+
+    if reload.config.utterance != config.utterance {
+        println!("utterance config changed, restart to apply");
+    }
+    if reload.config.runner != config.runner {
+        println!("runner config changed, restart to apply");
+    }
+    if reload.config.features != config.features {
+        println!("feature models changed, restart to apply");
+    }
+    config.models = reload.config.models;
+    config.matchers = reload.config.matchers;
+    config.intents = reload.config.intents;
+    config.model_cache = reload.config.model_cache;
+    println!(
+        "reloaded config: {} model(s) loaded, {} removed, {} rule(s), {} intent(s)",
+        changed,
+        reload.removed.len(),
+        config.matchers.len(),
+        config.intents.len()
+    );
+    Ok(())
+}
+
+/// Runs actions, exiting the process for exit actions.
+fn handle_action(action: &Action, ctx: &ActionContext) {
+    if let Some(code) = action.exit_code() {
+        println!("{}: exiting with {}", ctx.rule, code);
+        std::process::exit(code);
+    }
+    action.run(ctx);
+}
+
+/// An utterance which is in the process of being recorded.
+struct Recording {
+    samples: Vec<f32>,
+    trigger_model: String,
+    peak_score: f32,
+    started_at: chrono::DateTime<chrono::Local>,
+    /// Sample position at the end of the recorded audio.
+    position: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holdout_keeps_clips_to_train_on() {
+        let clips: Vec<usize> = (0..10).collect();
+        assert_eq!(
+            holdout(&clips, 0.2),
+            (vec![0, 1, 2, 3, 5, 6, 7, 8], vec![4, 9])
+        );
+        assert_eq!(holdout(&clips[..3], 0.2), (vec![0, 1], vec![2]));
+        assert_eq!(holdout(&clips[..1], 0.5), (vec![0], vec![]));
+        assert_eq!(holdout(&clips[..2], 1.), (vec![0], vec![1]));
+        assert_eq!(holdout(&clips[..4], 0.), (vec![0, 1, 2, 3], vec![]));
+    }
 }
@@ -1,121 +1,486 @@
-use crate::MatchConfig;
-use std::collections::BTreeMap;
-use std::time::Instant;
+use crate::{
+    Action, ActionContext, ActionHandler, Clock, IntentMatch, MatchConfig, MatchStageConfig,
+    StageActivation, WallClock,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Describes progress of a rule through its chain of stages.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatchEvent {
+    /// A stage of a rule matched. elapsed is the time since the previous stage matched.
+    StageMatched {
+        rule: String,
+        stage: usize,
+        activations: Vec<(String, f32)>,
+        elapsed: Duration,
+    },
+    /// A stage matched, but was rejected due to an inhibitor, resetting the rule.
+    StageInhibited {
+        rule: String,
+        stage: usize,
+        by: String,
+    },
+    /// A rule was reset as the stage did not match within its timeout.
+    StageTimedOut {
+        rule: String,
+        stage: usize,
+        elapsed: Duration,
+    },
+    /// All stages of a rule matched, and its action was run. elapsed is the time
+    /// since the first stage matched.
+    RuleFired {
+        rule: String,
+        chain: Vec<StageActivation>,
+        elapsed: Duration,
+    },
+    /// An utterance was transcribed, matching intent if any. See
+    /// [crate::IntentMatcher::eval_transcript].
+    Transcribed {
+        path: PathBuf,
+        text: String,
+        intent: Option<IntentMatch>,
+    },
+}
+
+impl std::fmt::Display for MatchEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatchEvent::StageMatched {
+                rule,
+                stage,
+                activations,
+                elapsed,
+            } => write!(
+                f,
+                "{}[{}]: Activated {:?} after {:.02}s",
+                rule,
+                stage,
+                activations,
+                elapsed.as_secs_f32()
+            ),
+            MatchEvent::StageInhibited { rule, stage, by } => {
+                write!(f, "{}[{}]: Inhibited by {}", rule, stage, by)
+            }
+            MatchEvent::StageTimedOut {
+                rule,
+                stage,
+                elapsed,
+            } => write!(
+                f,
+                "{}[{}]: Timeout after {:.02}s",
+                rule,
+                stage,
+                elapsed.as_secs_f32()
+            ),
+            MatchEvent::RuleFired {
+                rule,
+                chain,
+                elapsed,
+            } => write!(
+                f,
+                "{}: Fired ({}) after {:.02}s",
+                rule,
+                chain
+                    .iter()
+                    .map(|a| format!("{}={:.02}", a.model, a.score))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                elapsed.as_secs_f32()
+            ),
+            MatchEvent::Transcribed { path, text, intent } => {
+                write!(f, "transcript for {:?}: {:?}", path, text)?;
+                match intent {
+                    Some(m) => write!(f, ", matched {} {:?}", m.intent, m.slots),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 enum StageResult {
     Noop,
-    Matched,
-    Timeout,
+    Matched(Vec<(String, f32)>),
+    Inhibited(String),
 }
 
-#[derive(Clone, Debug, Default)]
-struct MatchStage {
+/// A model which must not activate within window_ms before a stage matches.
+#[derive(Clone, Debug)]
+struct Inhibitor {
     model: String,
     activation_threshold: f32,
+    window_ms: usize,
+    last_seen: Option<Duration>,
+}
+
+#[derive(Clone, Debug)]
+enum StageKind {
+    /// Matches when any one of the models activates.
+    AnyOf(Vec<String>),
+    /// Matches when all of the models have activated within window_ms of each other.
+    AllOf {
+        models: Vec<String>,
+        window_ms: usize,
+        seen: Vec<Option<(Duration, f32)>>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct MatchStage {
+    kind: StageKind,
+    activation_threshold: f32,
     timeout_ms: usize,
+    optional: bool,
+    inhibitors: Vec<Inhibitor>,
+}
+
+impl TryFrom<MatchStageConfig> for MatchStage {
+    type Error = anyhow::Error;
+
+    fn try_from(stage: MatchStageConfig) -> Result<Self, Self::Error> {
+        let kind = match (
+            stage.model,
+            stage.any_of.is_empty(),
+            stage.all_of.is_empty(),
+        ) {
+            (Some(model), true, true) => StageKind::AnyOf(vec![model]),
+            (None, false, true) => StageKind::AnyOf(stage.any_of),
+            (None, true, false) => StageKind::AllOf {
+                seen: vec![None; stage.all_of.len()],
+                models: stage.all_of,
+                window_ms: stage.window_ms.unwrap_or(1000),
+            },
+            _ => anyhow::bail!("exactly one of model, any_of or all_of must be specified"),
+        };
+
+        Ok(Self {
+            kind,
+            timeout_ms: stage.timeout_ms.unwrap_or(3200),
+            activation_threshold: stage.activation_threshold.unwrap_or(0.5),
+            optional: stage.optional,
+            inhibitors: stage
+                .unless
+                .into_iter()
+                .map(|i| Inhibitor {
+                    model: i.model,
+                    activation_threshold: i.activation_threshold.unwrap_or(0.5),
+                    window_ms: i.window_ms.unwrap_or(1000),
+                    last_seen: None,
+                })
+                .collect(),
+        })
+    }
 }
 
 impl MatchStage {
-    fn eval(
-        &mut self,
-        started_time: Option<&Instant>,
-        activations: &Vec<(String, f32)>,
-    ) -> StageResult {
-        if let Some((_, amt)) = activations.iter().find(|(n, _)| n == &self.model) {
-            if amt >= &self.activation_threshold {
-                return StageResult::Matched;
+    /// The models which can match this stage.
+    fn models(&self) -> &[String] {
+        match &self.kind {
+            StageKind::AnyOf(models) => models,
+            StageKind::AllOf { models, .. } => models,
+        }
+    }
+
+    /// Records activations of inhibitors. This must be called every frame, regardless of
+    /// which stage is current, so inhibitors have a history to look back on.
+    fn observe(&mut self, now: Duration, activations: &[(Arc<str>, f32)]) {
+        for i in self.inhibitors.iter_mut() {
+            if activations
+                .iter()
+                .any(|(n, amt)| **n == *i.model && *amt >= i.activation_threshold)
+            {
+                i.last_seen = Some(now);
             }
         }
+    }
+
+    fn inhibited_by(&self, now: Duration) -> Option<&String> {
+        self.inhibitors
+            .iter()
+            .find(|i| {
+                i.last_seen
+                    .is_some_and(|at| now.saturating_sub(at).as_millis() <= i.window_ms as u128)
+            })
+            .map(|i| &i.model)
+    }
+
+    fn eval(&mut self, now: Duration, activations: &[(Arc<str>, f32)]) -> StageResult {
+        let threshold = self.activation_threshold;
+        let active = |model: &String| {
+            activations
+                .iter()
+                .find(|(n, amt)| **n == **model && *amt >= threshold)
+                .map(|(_, amt)| *amt)
+        };
+
+        match &mut self.kind {
+            StageKind::AnyOf(models) => {
+                // Report the strongest activation if several models fire together.
+                let best = models
+                    .iter()
+                    .filter_map(|m| active(m).map(|amt| (m, amt)))
+                    .max_by(|a, b| a.1.total_cmp(&b.1));
+                if let Some((model, amt)) = best {
+                    let matched = vec![(model.clone(), amt)];
+                    return self.check_inhibitors(now, matched);
+                }
+            }
+            StageKind::AllOf {
+                models,
+                window_ms,
+                seen,
+            } => {
+                for (model, seen) in models.iter().zip(seen.iter_mut()) {
+                    if let Some(amt) = active(model) {
+                        *seen = Some((now, amt));
+                    }
+                }
 
-        if let Some(started) = started_time {
-            if Instant::now().duration_since(*started).as_millis() > self.timeout_ms as u128 {
-                return StageResult::Timeout;
+                let all_recent = seen.iter().all(|s| {
+                    s.is_some_and(|(at, _)| {
+                        now.saturating_sub(at).as_millis() <= *window_ms as u128
+                    })
+                });
+                if all_recent {
+                    let out = models
+                        .iter()
+                        .zip(seen.iter_mut())
+                        .map(|(m, s)| (m.clone(), s.take().unwrap().1))
+                        .collect();
+                    return self.check_inhibitors(now, out);
+                }
             }
         }
 
         StageResult::Noop
     }
+
+    fn check_inhibitors(&self, now: Duration, matched: Vec<(String, f32)>) -> StageResult {
+        match self.inhibited_by(now) {
+            Some(model) => StageResult::Inhibited(model.clone()),
+            None => StageResult::Matched(matched),
+        }
+    }
+
+    fn timed_out(&self, now: Duration, started: Duration) -> bool {
+        now.saturating_sub(started).as_millis() > self.timeout_ms as u128
+    }
+
+    /// Forgets the models seen by an all_of stage, so they can't carry over to the next
+    /// attempt at the rule.
+    fn reset(&mut self) {
+        if let StageKind::AllOf { seen, .. } = &mut self.kind {
+            seen.iter_mut().for_each(|s| *s = None);
+        }
+    }
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 struct MatchState {
-    current_stage: Option<(usize, Instant)>,
+    /// The index of the next stage to match, and when the previous stage matched.
+    current_stage: Option<(usize, Duration)>,
     stages: Vec<MatchStage>,
-    action: String,
+    action: Action,
+    chain: Vec<StageActivation>,
+    /// When the first stage of the in-progress chain matched.
+    first_matched: Option<Duration>,
 }
 
 impl MatchState {
-    fn do_action(&mut self, name: &String) {
-        let mut spl = self.action.split(":");
-        match spl.next() {
-            Some("exit") => {
-                let code: i32 = spl.next().map(|x| x.parse().unwrap_or(0)).unwrap_or(0);
-                std::process::exit(code);
-            }
-            Some("exec") => {
-                use std::process::Command;
+    /// The stages which could match next if the rule were at stage idx: that stage and
+    /// any following it which are reachable by skipping optional stages.
+    fn reachable(&self, idx: usize) -> impl Iterator<Item = &MatchStage> {
+        let mut done = false;
+        self.stages[idx.min(self.stages.len())..]
+            .iter()
+            .take_while(move |s| {
+                let take = !done;
+                done = !s.optional;
+                take
+            })
+    }
+
+    fn activated(
+        &mut self,
+        idx: usize,
+        matched: Vec<(String, f32)>,
+        timestamp: chrono::DateTime<chrono::Local>,
+    ) {
+        self.chain
+            .extend(matched.into_iter().map(|(model, score)| StageActivation {
+                stage: idx,
+                model,
+                score,
+                timestamp,
+            }));
+    }
+
+    /// Completes the in-progress chain, returning the context for the action to run.
+    fn fire(
+        &mut self,
+        name: &str,
+        now: Duration,
+        timestamp: chrono::DateTime<chrono::Local>,
+        events: &mut Vec<MatchEvent>,
+    ) -> ActionContext {
+        let mut ctx = ActionContext::new(name);
+        ctx.timestamp = Some(timestamp);
+        if let Some(last) = self.chain.last() {
+            ctx.model = Some(last.model.clone());
+            ctx.score = Some(last.score);
+        }
+        // Report the branch taken through the chain, such as `{stage1}` = `living_room`.
+        for a in self.chain.iter() {
+            ctx.vars
+                .entry(format!("stage{}", a.stage))
+                .and_modify(|m| {
+                    m.push('+');
+                    m.push_str(&a.model)
+                })
+                .or_insert(a.model.clone());
+        }
+        // Skipped optional stages render as empty.
+        for i in 0..self.stages.len() {
+            ctx.vars.entry(format!("stage{}", i)).or_default();
+        }
+        ctx.chain = std::mem::take(&mut self.chain);
+        self.stages.iter_mut().for_each(|s| s.reset());
 
-                let mut c = Command::new(spl.next().unwrap());
-                let cmd = c.current_dir(std::env::current_dir().unwrap()).args(spl);
-                println!("spawning: {:?}", &cmd);
-                println!("result: {:?}", cmd.spawn());
+        events.push(MatchEvent::RuleFired {
+            rule: name.to_string(),
+            chain: ctx.chain.clone(),
+            elapsed: self
+                .first_matched
+                .take()
+                .map(|t| now.saturating_sub(t))
+                .unwrap_or_default(),
+        });
+        ctx
+    }
+
+    /// Tries to match the stage at idx, or any stage after it which is reachable by
+    /// skipping optional stages. Each stage can only match within its own timeout of the
+    /// previous match at started.
+    fn eval_from(
+        &mut self,
+        idx: usize,
+        now: Duration,
+        started: Option<Duration>,
+        activations: &[(Arc<str>, f32)],
+    ) -> (usize, StageResult) {
+        for i in idx..self.stages.len() {
+            if !started.is_some_and(|started| self.stages[i].timed_out(now, started)) {
+                match self.stages[i].eval(now, activations) {
+                    StageResult::Noop => {}
+                    res => return (i, res),
+                }
             }
-            _ => {
-                println!("{}: ignoring unhandled action {}", name, self.action);
+            if !self.stages[i].optional {
+                break;
             }
-        };
+        }
+        (idx, StageResult::Noop)
     }
 
-    fn eval(&mut self, name: &String, activations: &Vec<(String, f32)>) {
-        match self.current_stage {
-            Some((idx, started)) => {
-                let res = self.stages[idx].eval(Some(&started), activations);
-
-                match res {
-                    StageResult::Noop => {
-                        println!(
-                            "{}[{}]: Awaiting activation ({:.02})",
-                            name,
-                            idx,
-                            self.stages[idx].timeout_ms as f32 / 1000.0
-                                - Instant::now().duration_since(started).as_secs_f32()
-                        );
-                    }
-                    StageResult::Timeout => {
-                        println!("{}[{}]: Timeout", name, idx);
-                        self.current_stage = None;
-                    }
-                    StageResult::Matched => {
-                        println!("{}[{}]: Activated", name, idx);
-                        if idx >= self.stages.len() - 1 {
-                            self.do_action(name);
-                            self.current_stage = None;
-                        } else {
-                            self.current_stage = Some((idx + 1, Instant::now()));
-                        }
-                    }
+    /// Advances the rule, returning the context of the action to run if it fired. Stages
+    /// which match are timestamped with timestamp, the local time at now.
+    fn eval(
+        &mut self,
+        name: &str,
+        now: Duration,
+        timestamp: chrono::DateTime<chrono::Local>,
+        activations: &[(Arc<str>, f32)],
+        events: &mut Vec<MatchEvent>,
+    ) -> Option<ActionContext> {
+        for stage in self.stages.iter_mut() {
+            stage.observe(now, activations);
+        }
+
+        let (idx, started) = match self.current_stage {
+            Some((idx, started)) => (idx, Some(started)),
+            None => (0, None),
+        };
+        let elapsed = started.map(|s| now.saturating_sub(s)).unwrap_or_default();
+
+        match self.eval_from(idx, now, started, activations) {
+            (stage, StageResult::Inhibited(by)) => {
+                events.push(MatchEvent::StageInhibited {
+                    rule: name.to_string(),
+                    stage,
+                    by,
+                });
+                self.reset();
+            }
+            (matched, StageResult::Matched(m)) => {
+                if started.is_none() {
+                    self.first_matched = Some(now);
                 }
+                events.push(MatchEvent::StageMatched {
+                    rule: name.to_string(),
+                    stage: matched,
+                    activations: m.clone(),
+                    elapsed,
+                });
+                self.activated(matched, m, timestamp);
+
+                if matched >= self.stages.len() - 1 {
+                    self.current_stage = None;
+                    return Some(self.fire(name, now, timestamp, events));
+                }
+                self.current_stage = Some((matched + 1, now));
             }
 
-            None => {
-                if !self.stages.is_empty() {
-                    if let StageResult::Matched = self.stages[0].eval(None, activations) {
-                        if self.stages.len() >= 2 {
-                            self.current_stage = Some((1, Instant::now()));
-                        } else {
-                            self.do_action(name);
-                        }
+            (_, StageResult::Noop) => {
+                let Some(started) = started else {
+                    // Still waiting for the first stage, which never times out.
+                    return None;
+                };
+
+                if self.reachable(idx).all(|s| s.timed_out(now, started)) {
+                    // If every remaining stage is optional, the rule has been satisfied.
+                    if self.stages[idx..].iter().all(|s| s.optional) {
+                        self.current_stage = None;
+                        return Some(self.fire(name, now, timestamp, events));
                     }
+                    events.push(MatchEvent::StageTimedOut {
+                        rule: name.to_string(),
+                        stage: idx,
+                        elapsed,
+                    });
+                    self.reset();
                 }
             }
         }
+        None
+    }
+
+    fn reset(&mut self) {
+        self.chain.clear();
+        self.current_stage = None;
+        self.first_matched = None;
+        self.stages.iter_mut().for_each(|s| s.reset());
     }
 }
 
-#[derive(Debug)]
 pub struct Matcher {
     matches: BTreeMap<String, MatchState>,
+    handler: Option<Box<dyn ActionHandler>>,
+    rule_handlers: BTreeMap<String, Box<dyn ActionHandler>>,
+    clock: Box<dyn Clock>,
+}
+
+impl std::fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Matcher")
+            .field("matches", &self.matches)
+            .field("handler", &self.handler.is_some())
+            .field("rule_handlers", &self.rule_handlers.keys())
+            .finish()
+    }
 }
 
 impl Default for Matcher {
@@ -128,31 +493,334 @@ impl Matcher {
     pub fn new() -> Self {
         let matches = BTreeMap::new();
 
-        Self { matches }
+        Self {
+            matches,
+            handler: None,
+            rule_handlers: BTreeMap::new(),
+            clock: Box::new(WallClock::default()),
+        }
+    }
+
+    /// Creates a matcher which measures stage timeouts and timestamps activations with
+    /// clock rather than wall time.
+    pub fn with_clock<C: Clock + 'static>(clock: C) -> Self {
+        let mut out = Self::new();
+        out.set_clock(clock);
+        out
+    }
+
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Sets the handler which receives the actions of rules without their own handler.
+    /// Without a handler, actions are run with [Action::run].
+    pub fn set_handler<H: ActionHandler + 'static>(&mut self, handler: H) {
+        self.handler = Some(Box::new(handler));
     }
 
-    pub fn add_rule(&mut self, name: String, rule: MatchConfig) {
+    /// Sets the handler which receives the action of the named rule.
+    pub fn set_rule_handler<H: ActionHandler + 'static>(
+        &mut self,
+        rule: &str,
+        handler: H,
+    ) -> Result<(), anyhow::Error> {
+        if !self.matches.contains_key(rule) {
+            anyhow::bail!("no such rule: {}", rule);
+        }
+        self.rule_handlers
+            .insert(rule.to_string(), Box::new(handler));
+        Ok(())
+    }
+
+    pub fn add_rule(&mut self, name: String, rule: MatchConfig) -> Result<(), anyhow::Error> {
         let mut state = MatchState {
-            action: rule.action,
-            ..MatchState::default()
+            current_stage: None,
+            stages: Vec::with_capacity(rule.chain.len()),
+            action: Action::try_from(&rule.action)?,
+            chain: Vec::new(),
+            first_matched: None,
         };
 
-        for stage in rule.chain {
-            state.stages.push(MatchStage {
-                model: stage.model,
-                timeout_ms: stage.timeout_ms.unwrap_or(3200),
-                activation_threshold: stage.activation_threshold.unwrap_or(0.5),
-                ..MatchStage::default()
-            })
+        for (i, stage) in rule.chain.into_iter().enumerate() {
+            state.stages.push(
+                MatchStage::try_from(stage)
+                    .map_err(|e| anyhow::anyhow!("{}: chain[{}]: {}", name, i, e))?,
+            );
         }
 
         self.matches.insert(name, state);
+        Ok(())
     }
 
-    pub fn eval(&mut self, activations: Vec<(String, f32)>) {
-        // println!("{:?}", activations);
+    /// Returns the models which can only match a stage after an earlier stage of some
+    /// rule has matched. Such models need not be run until [Matcher::awaited_models]
+    /// includes them. Models which could start a rule or inhibit a stage are never gated.
+    pub fn gated_models(&self) -> BTreeSet<String> {
+        let mut all = BTreeSet::new();
+        let mut ungated = BTreeSet::new();
+        for m in self.matches.values() {
+            for stage in m.stages.iter() {
+                all.extend(stage.models().iter().cloned());
+                ungated.extend(stage.inhibitors.iter().map(|i| i.model.clone()));
+            }
+            for stage in m.reachable(0) {
+                ungated.extend(stage.models().iter().cloned());
+            }
+        }
+        all.difference(&ungated).cloned().collect()
+    }
+
+    /// Returns the models of the stages which rules in progress are waiting for.
+    pub fn awaited_models(&self) -> BTreeSet<String> {
+        let mut out = BTreeSet::new();
+        for m in self.matches.values() {
+            if let Some((idx, _)) = m.current_stage {
+                for stage in m.reachable(idx) {
+                    out.extend(stage.models().iter().cloned());
+                }
+            }
+        }
+        out
+    }
+
+    /// Advances all rules with the activations of a frame, returning what happened.
+    pub fn eval(&mut self, activations: &[(Arc<str>, f32)]) -> Vec<MatchEvent> {
+        let mut events = Vec::new();
+        let now = self.clock.now();
+        let timestamp = self.clock.timestamp(now);
         for (name, m) in self.matches.iter_mut() {
-            m.eval(name, &activations);
+            if let Some(ctx) = m.eval(name, now, timestamp, activations, &mut events) {
+                match self.rule_handlers.get_mut(name).or(self.handler.as_mut()) {
+                    Some(handler) => handler.handle(&m.action, &ctx),
+                    None => m.action.run(&ctx),
+                }
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StreamClock;
+    use std::sync::mpsc::{Receiver, channel};
+
+    struct Harness {
+        matcher: Matcher,
+        clock: StreamClock,
+        fired: Receiver<(Action, ActionContext)>,
+        /// The events of the last frame.
+        events: Vec<MatchEvent>,
+    }
+
+    impl Harness {
+        fn new(chain: &str) -> Self {
+            let clock = StreamClock::new();
+            let (send, fired) = channel();
+            let mut matcher = Matcher::with_clock(clock.clone());
+            matcher.set_handler(send);
+            let chain: Vec<MatchStageConfig> = serde_yaml::from_str(chain).unwrap();
+            matcher
+                .add_rule(
+                    "rule".to_string(),
+                    MatchConfig {
+                        chain,
+                        action: "exit:0".into(),
+                    },
+                )
+                .unwrap();
+            Self {
+                matcher,
+                clock,
+                fired,
+                events: Vec::new(),
+            }
         }
+
+        /// Evaluates a frame at ms into the stream, returning the context if the rule fired.
+        fn frame(&mut self, ms: u64, active: &[&str]) -> Option<ActionContext> {
+            self.clock.set(ms * 16);
+            let activations: Vec<(Arc<str>, f32)> =
+                active.iter().map(|m| (Arc::from(*m), 0.9)).collect();
+            self.events = self.matcher.eval(&activations);
+            self.fired.try_recv().ok().map(|(_, ctx)| ctx)
+        }
+    }
+
+    fn models(ctx: &ActionContext) -> Vec<&str> {
+        ctx.chain.iter().map(|a| a.model.as_str()).collect()
+    }
+
+    #[test]
+    fn any_of_matches_either_model() {
+        let mut h = Harness::new("[{any_of: [a, b]}, {model: c}]");
+        assert!(h.frame(0, &["b"]).is_none());
+        let ctx = h.frame(500, &["c"]).unwrap();
+        assert_eq!(models(&ctx), ["b", "c"]);
+        assert_eq!(ctx.vars["stage0"], "b");
+
+        assert!(h.frame(1000, &["a"]).is_none());
+        assert_eq!(models(&h.frame(1500, &["c"]).unwrap()), ["a", "c"]);
+    }
+
+    #[test]
+    fn all_of_needs_every_model_within_window() {
+        let mut h = Harness::new("[{all_of: [a, b], window_ms: 500}]");
+        assert!(h.frame(0, &["a"]).is_none());
+        assert_eq!(models(&h.frame(300, &["b"]).unwrap()), ["a", "b"]);
+
+        assert!(h.frame(1000, &["a"]).is_none());
+        assert!(h.frame(1600, &["b"]).is_none());
+        assert!(h.frame(1700, &["a"]).is_some());
+    }
+
+    #[test]
+    fn all_of_forgets_models_seen_before_a_timeout() {
+        let mut h =
+            Harness::new("[{model: w}, {all_of: [a, b], window_ms: 2000, timeout_ms: 1000}]");
+        assert!(h.frame(0, &["w"]).is_none());
+        assert!(h.frame(500, &["a"]).is_none());
+        // Times out, resetting the rule.
+        assert!(h.frame(1100, &[]).is_none());
+
+        // a was seen by the previous attempt, so doesn't count towards this one.
+        assert!(h.frame(1200, &["w"]).is_none());
+        assert!(h.frame(1300, &["b"]).is_none());
+        assert_eq!(models(&h.frame(1400, &["a"]).unwrap()), ["w", "a", "b"]);
+    }
+
+    #[test]
+    fn optional_stages_can_be_skipped() {
+        let chain = "[{model: w}, {model: o, optional: true, timeout_ms: 500}, {model: c, timeout_ms: 2000}]";
+        let mut h = Harness::new(chain);
+        assert!(h.frame(0, &["w"]).is_none());
+        assert!(h.frame(200, &["o"]).is_none());
+        let ctx = h.frame(400, &["c"]).unwrap();
+        assert_eq!(models(&ctx), ["w", "o", "c"]);
+        assert_eq!(ctx.vars["stage1"], "o");
+
+        // Skipping the optional stage, c has its own timeout rather than that of o.
+        assert!(h.frame(1000, &["w"]).is_none());
+        let ctx = h.frame(2000, &["c"]).unwrap();
+        assert_eq!(models(&ctx), ["w", "c"]);
+        assert_eq!(ctx.vars["stage1"], "");
+
+        // Once o's timeout passes it can no longer match.
+        assert!(h.frame(3000, &["w"]).is_none());
+        assert!(h.frame(3600, &["o"]).is_none());
+        assert_eq!(models(&h.frame(3700, &["c"]).unwrap()), ["w", "c"]);
+    }
+
+    #[test]
+    fn trailing_optional_stages_fire_on_timeout() {
+        let mut h = Harness::new("[{model: w}, {model: o, optional: true, timeout_ms: 500}]");
+        assert!(h.frame(0, &["w"]).is_none());
+        assert!(h.frame(400, &[]).is_none());
+        assert_eq!(models(&h.frame(600, &[]).unwrap()), ["w"]);
+    }
+
+    fn inhibited_by(events: &[MatchEvent]) -> Option<(usize, &str)> {
+        events.iter().find_map(|e| match e {
+            MatchEvent::StageInhibited { stage, by, .. } => Some((*stage, by.as_str())),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn inhibitors_reject_stages_within_their_window() {
+        let mut h = Harness::new("[{model: w, unless: [{model: n, window_ms: 500}]}, {model: c}]");
+        assert!(h.frame(0, &["n"]).is_none());
+        assert!(h.frame(400, &["w"]).is_none());
+        assert_eq!(inhibited_by(&h.events), Some((0, "n")));
+        // The rule was reset, so c alone doesn't complete it.
+        assert!(h.frame(600, &["c"]).is_none());
+
+        // Once the window has passed, the stage matches.
+        assert!(h.frame(1000, &["w"]).is_none());
+        assert_eq!(inhibited_by(&h.events), None);
+        assert_eq!(models(&h.frame(1200, &["c"]).unwrap()), ["w", "c"]);
+    }
+
+    #[test]
+    fn inhibitors_reject_activations_in_the_same_frame() {
+        let mut h = Harness::new("[{model: w, unless: [{model: n}]}]");
+        assert!(h.frame(0, &["w", "n"]).is_none());
+        assert_eq!(inhibited_by(&h.events), Some((0, "n")));
+        assert!(h.frame(2000, &["w"]).is_some());
+    }
+
+    #[test]
+    fn inhibitors_of_later_stages_are_watched_from_the_start() {
+        let mut h = Harness::new("[{model: w}, {model: c, unless: [{model: n, window_ms: 500}]}]");
+        // n activates before the rule reaches the stage it inhibits.
+        assert!(h.frame(0, &["n"]).is_none());
+        assert!(h.frame(100, &["w"]).is_none());
+        assert!(h.frame(300, &["c"]).is_none());
+        assert_eq!(inhibited_by(&h.events), Some((1, "n")));
+
+        assert!(h.frame(1000, &["w"]).is_none());
+        assert_eq!(models(&h.frame(1200, &["c"]).unwrap()), ["w", "c"]);
+    }
+
+    #[test]
+    fn activations_are_timestamped_by_the_clock() {
+        let mut h = Harness::new("[{model: w}, {model: c}]");
+        let started_at = h.clock.timestamp(Duration::ZERO);
+        assert!(h.frame(1000, &["w"]).is_none());
+        let ctx = h.frame(1500, &["c"]).unwrap();
+        let offsets: Vec<_> = ctx
+            .chain
+            .iter()
+            .map(|a| (a.timestamp - started_at).num_milliseconds())
+            .collect();
+        assert_eq!(offsets, [1000, 1500]);
+        assert_eq!(ctx.timestamp, Some(ctx.chain[1].timestamp));
+    }
+
+    fn sorted(models: BTreeSet<String>) -> Vec<String> {
+        models.into_iter().collect()
+    }
+
+    #[test]
+    fn models_of_later_stages_are_gated() {
+        let mut h = Harness::new(
+            "[{model: w, optional: true}, {model: x}, {any_of: [a, b], unless: [{model: n}]}, {model: c, optional: true}, {model: d}]",
+        );
+        assert_eq!(sorted(h.matcher.gated_models()), ["a", "b", "c", "d"]);
+
+        // Models which start another rule, or inhibit any stage, are never gated.
+        let chain = serde_yaml::from_str("[{model: d}, {model: e, unless: [{model: c}]}]").unwrap();
+        h.matcher
+            .add_rule(
+                "other".to_string(),
+                MatchConfig {
+                    chain,
+                    action: "exit:0".into(),
+                },
+            )
+            .unwrap();
+        assert_eq!(sorted(h.matcher.gated_models()), ["a", "b", "e"]);
+    }
+
+    #[test]
+    fn awaited_models_follow_the_rule_in_progress() {
+        let mut h =
+            Harness::new("[{model: w}, {model: o, optional: true}, {any_of: [a, b]}, {model: c}]");
+        assert!(h.matcher.awaited_models().is_empty());
+        assert!(h.frame(0, &["w"]).is_none());
+        assert_eq!(sorted(h.matcher.awaited_models()), ["a", "b", "o"]);
+        // Skips the optional stage.
+        assert!(h.frame(100, &["b"]).is_none());
+        assert_eq!(sorted(h.matcher.awaited_models()), ["c"]);
+        assert!(h.frame(200, &["c"]).is_some());
+        assert!(h.matcher.awaited_models().is_empty());
+
+        // A rule which times out waits for nothing.
+        assert!(h.frame(1000, &["w"]).is_none());
+        assert!(!h.matcher.awaited_models().is_empty());
+        assert!(h.frame(5000, &[]).is_none());
+        assert!(h.matcher.awaited_models().is_empty());
     }
 }
@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, sync_channel};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::{Config, ModelConfig, NamedModel};
+
+/// A new config which has been validated, along with the models that need to change in
+/// the [crate::Runner] to match it.
+pub struct Reload {
+    pub config: Config,
+    /// Models which are new, or whose config or file changed, loaded and ready to add.
+    pub models: Vec<NamedModel>,
+    /// Names of models no longer in the config.
+    pub removed: Vec<String>,
+}
+
+/// ConfigWatcher reloads a config file when it or any model file it references changes,
+/// or when the process receives SIGHUP. Models are loaded in the background, and invalid
+/// configs are reported and otherwise ignored, so the previous config remains in use.
+///
+/// Each reload must be acknowledged with [ConfigWatcher::applied] before the next one is
+/// loaded, so a reload which fails to apply is compared against the config still in use.
+pub struct ConfigWatcher {
+    recv: Option<Receiver<Reload>>,
+    acks: SyncSender<bool>,
+    /// The SIGHUP handler, removed once the watcher is dropped.
+    #[cfg(unix)]
+    hangup: signal_hook::SigId,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// The models of the last config which was successfully loaded, and the modification
+/// times of the files seen when last attempting to load.
+struct Loaded {
+    models: BTreeMap<String, (ModelConfig, ModelTimes)>,
+    attempted: Vec<ModelTimes>,
+}
+
+fn modified<P: AsRef<Path>>(path: P) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The modification times of a model file and of its verifier.
+type ModelTimes = (Option<SystemTime>, Option<SystemTime>);
+
+fn model_modified(m: &ModelConfig) -> ModelTimes {
+    (
+        modified(&m.path),
+        m.verifier.as_ref().and_then(|v| modified(&v.path)),
+    )
+}
+
+impl Loaded {
+    fn new(path: &Path, config: &Config) -> Self {
+        let mut out = Self {
+            models: config
+                .models
+                .iter()
+                .map(|(name, m)| (name.clone(), (m.clone(), model_modified(m))))
+                .collect(),
+            attempted: Vec::new(),
+        };
+        out.attempted = out.snapshot(path);
+        out
+    }
+
+    fn snapshot(&self, path: &Path) -> Vec<ModelTimes> {
+        std::iter::once((modified(path), None))
+            .chain(self.models.values().map(|(m, _)| model_modified(m)))
+            .collect()
+    }
+
+    /// Whether the config or a model file changed since the last attempt to load.
+    fn changed(&self, path: &Path) -> bool {
+        self.snapshot(path) != self.attempted
+    }
+}
+
+impl ConfigWatcher {
+    /// Starts watching the config file at path, which is currently loaded as config.
+    pub fn start<P: Into<PathBuf>>(path: P, config: &Config) -> Result<Self, anyhow::Error> {
+        let path = path.into();
+        let (send, recv) = sync_channel(1);
+        let (acks, ack_recv) = sync_channel(1);
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let hangup = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        let hangup_id = signal_hook::flag::register(signal_hook::consts::SIGHUP, hangup.clone())?;
+
+        let loaded = Loaded::new(&path, config);
+        let shutdown2 = shutdown.clone();
+        let thread = Some(thread::spawn(move || {
+            ConfigWatcher::mainloop(send, ack_recv, shutdown2, hangup, path, loaded);
+        }));
+
+        let out = Self {
+            shutdown,
+            thread,
+            recv: Some(recv),
+            acks,
+            #[cfg(unix)]
+            hangup: hangup_id,
+        };
+
+        Ok(out)
+    }
+
+    pub fn take_receiver(&mut self) -> Option<Receiver<Reload>> {
+        self.recv.take()
+    }
+
+    /// Reports whether the last reload received was applied. Until it is, the watcher
+    /// keeps comparing against the previous config and loads nothing new.
+    pub fn applied(&self, ok: bool) {
+        self.acks.try_send(ok).ok();
+    }
+
+    fn mainloop(
+        tx: SyncSender<Reload>,
+        acks: Receiver<bool>,
+        shutdown: Arc<AtomicBool>,
+        hangup: Arc<AtomicBool>,
+        path: PathBuf,
+        mut loaded: Loaded,
+    ) {
+        loop {
+            thread::sleep(Duration::from_millis(250));
+            if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+                return;
+            }
+
+            let signalled = hangup.swap(false, std::sync::atomic::Ordering::SeqCst);
+            if !signalled && !loaded.changed(&path) {
+                continue;
+            }
+            // Don't retry a broken config until something changes again.
+            loaded.attempted = loaded.snapshot(&path);
+
+            let reload = match ConfigWatcher::load(&path, &loaded, signalled) {
+                Ok(reload) => reload,
+                Err(errs) => {
+                    for e in errs {
+                        println!("{:?}: {}", path, e);
+                    }
+                    println!("{:?}: keeping previous config", path);
+                    continue;
+                }
+            };
+            if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+                return;
+            }
+
+            let pending = Loaded::new(&path, &reload.config);
+            if let Err(e) = tx.send(reload) {
+                println!("failed send, config watcher thread shutting down! {:?}", e);
+                return;
+            }
+
+            // Only move on to the new config once it is in use.
+            loop {
+                match acks.recv_timeout(Duration::from_millis(250)) {
+                    Ok(true) => {
+                        loaded = pending;
+                        break;
+                    }
+                    Ok(false) => break,
+                    Err(RecvTimeoutError::Timeout) => {
+                        if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+                            return;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        }
+    }
+
+    /// Loads and validates the config, and loads the models which changed. All models
+    /// are reloaded if force is set.
+    fn load(path: &Path, loaded: &Loaded, force: bool) -> Result<Reload, Vec<String>> {
+        let config = Config::load(path).map_err(|e| vec![e.to_string()])?;
+        let errs = config.validate(false);
+        if !errs.is_empty() {
+            return Err(errs.iter().map(|e| e.to_string()).collect());
+        }
+
+        let cache = config.model_cache();
+        let mut models = Vec::new();
+        let mut errs = Vec::new();
+        for (name, m) in config.models.iter() {
+            let unchanged = loaded
+                .models
+                .get(name)
+                .is_some_and(|(prev, at)| prev == m && model_modified(m) == *at);
+            if unchanged && !force {
+                continue;
+            }
+            match NamedModel::from_config(name, m, cache.as_ref()) {
+                Ok(model) => models.push(model),
+                Err(e) => errs.push(format!(
+                    "models.{}.path: failed loading {:?}: {:#}",
+                    name, m.path, e
+                )),
+            }
+        }
+        if !errs.is_empty() {
+            return Err(errs);
+        }
+
+        let removed = loaded
+            .models
+            .keys()
+            .filter(|name| !config.models.contains_key(*name))
+            .cloned()
+            .collect();
+        Ok(Reload {
+            config,
+            models,
+            removed,
+        })
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        signal_hook::low_level::unregister(self.hangup);
+        self.shutdown
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        if let Some(hnd) = self.thread.take() {
+            hnd.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(path: &Path, models: &[&str]) {
+        let mut yaml = String::from("matchers: {}\nmodels:\n");
+        for name in models {
+            yaml += &format!(
+                "  {}:\n    path: {}/models/hey_rhasspy_v0.1.onnx\n",
+                name,
+                env!("CARGO_MANIFEST_DIR")
+            );
+        }
+        std::fs::write(path, yaml).unwrap();
+    }
+
+    #[test]
+    fn rejected_reloads_keep_previous_state() {
+        let dir = std::env::temp_dir().join(format!("oww-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+        write_config(&path, &["a", "b"]);
+        let config = Config::load(&path).unwrap();
+
+        let mut watcher = ConfigWatcher::start(&path, &config).unwrap();
+        let reloads = watcher.take_receiver().unwrap();
+        let next = |models: &[&str]| {
+            // Move the modification time on explicitly, as filesystems with coarse
+            // timestamps may not see a quick rewrite as a change.
+            let before = modified(&path).unwrap();
+            write_config(&path, models);
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(before + Duration::from_secs(1))
+                .unwrap();
+            reloads.recv_timeout(Duration::from_secs(10)).unwrap()
+        };
+
+        let reload = next(&["a"]);
+        assert_eq!(reload.removed, vec!["b".to_string()]);
+        watcher.applied(false);
+
+        // b is still loaded, so it is removed again, and a is unchanged.
+        let reload = next(&["a"]);
+        assert_eq!(reload.removed, vec!["b".to_string()]);
+        assert!(reload.models.is_empty());
+        watcher.applied(true);
+
+        let reload = next(&["a", "c"]);
+        assert!(reload.removed.is_empty());
+        assert_eq!(reload.models.len(), 1);
+        watcher.applied(true);
+
+        drop(watcher);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,13 +1,17 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::sync::atomic::AtomicBool;
 use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use crate::Embedding;
-use circular_buffer::CircularBuffer;
+use crate::template::Scratch;
+use crate::{
+    Embedding, Matcher, ModelCache, ModelConfig, ModelSource, RunnerConfig, Template, Verifier,
+};
+use rayon::prelude::*;
 use tract_onnx::prelude::*;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ModelFilters {
     pub scale: f32,        // how much to scale model_val
     pub clamp: (f32, f32), // bounds for output value
@@ -28,61 +32,451 @@ impl ModelFilters {
     }
 }
 
+/// What computes the score of a [NamedModel].
+enum Classifier {
+    Graph(Box<TypedRunnableModel<TypedModel>>),
+    /// A template, with the buffers it is scored with.
+    Template(Template, Mutex<Scratch>),
+}
+
 pub struct NamedModel {
-    name: String,
-    model: TypedRunnableModel<TypedModel>,
+    name: Arc<str>,
+    path: String,
+    model: Classifier,
     filters: ModelFilters,
+    /// The number of embeddings in the input of the model.
+    window: usize,
+    enabled: AtomicBool,
+    groups: Vec<String>,
+    /// Checks activations, along with the probability it must give for them.
+    verifier: Option<(Verifier, f32)>,
+}
+
+/// A description of a model loaded in a [Runner].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelInfo {
+    pub name: String,
+    pub path: String,
+    pub filters: ModelFilters,
+    pub window: usize,
+    pub enabled: bool,
+    pub groups: Vec<String>,
 }
 
 impl NamedModel {
+    /// Loads a classifier, or a [Template] if path is a `.template` file.
     pub fn new<S: Into<String>>(name: S, path: S, scale: f32) -> Result<Self, anyhow::Error> {
-        let model = tract_onnx::onnx()
-            // load the model
-            .model_for_path(path.into())?
-            .into_optimized()?
-            .into_runnable()?;
+        let path = path.into();
+        if Template::is_template(&path) {
+            let template = Template::load(&path)?;
+            return Ok(Self::from_template(name.into(), template, path, scale));
+        }
+        Self::from_source(name, ModelSource::Path(path.clone().into()), path, scale)
+    }
+
+    /// Loads a model from source. The path is only used to describe the model, such as
+    /// in [ModelInfo].
+    pub fn from_source(
+        name: impl Into<String>,
+        source: ModelSource,
+        path: impl Into<String>,
+        scale: f32,
+    ) -> Result<Self, anyhow::Error> {
+        Self::from_typed(name.into(), source.typed(None)?, path.into(), scale)
+    }
+
+    /// Optimizes a typed model, which must take a single [1, N, 96] input.
+    fn from_typed(
+        name: String,
+        model: TypedModel,
+        path: String,
+        scale: f32,
+    ) -> Result<Self, anyhow::Error> {
+        let window =
+            NamedModel::input_window(&model).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        let model = model.into_optimized()?.into_runnable()?;
+        Ok(Self::with_classifier(
+            name,
+            Classifier::Graph(Box::new(model)),
+            window,
+            path,
+            scale,
+        ))
+    }
+
+    /// Detects the phrase of a template, scoring the usual window of [NUM_EMBEDDINGS].
+    pub fn from_template(
+        name: impl Into<String>,
+        mut template: Template,
+        path: impl Into<String>,
+        scale: f32,
+    ) -> Self {
+        template.normalize();
+        Self::with_classifier(
+            name.into(),
+            Classifier::Template(template, Mutex::default()),
+            NUM_EMBEDDINGS,
+            path.into(),
+            scale,
+        )
+    }
 
-        let name = name.into();
+    fn with_classifier(
+        name: String,
+        model: Classifier,
+        window: usize,
+        path: String,
+        scale: f32,
+    ) -> Self {
         let filters = ModelFilters {
             scale,
             ..ModelFilters::default()
         };
-        Ok(Self {
-            name,
+        Self {
+            name: name.into(),
+            path,
             model,
             filters,
-        })
+            window,
+            enabled: AtomicBool::new(true),
+            groups: Vec::new(),
+            verifier: None,
+        }
+    }
+
+    /// Loads a model as configured, through cache if one is given. Templates aren't
+    /// cached, as they load quickly.
+    pub fn from_config(
+        name: &str,
+        config: &ModelConfig,
+        cache: Option<&ModelCache>,
+    ) -> Result<Self, anyhow::Error> {
+        let scale = config.scale.unwrap_or(1.);
+        let model = match cache {
+            Some(cache) if !Template::is_template(&config.path) => {
+                let source = cache.get(config.path.as_str().into(), None)?;
+                Self::from_source(name, source, config.path.as_str(), scale)?
+            }
+            _ => Self::new(name, config.path.as_str(), scale)?,
+        }
+        .with_groups(config.groups.clone())
+        .with_enabled(config.enabled);
+        match &config.verifier {
+            Some(v) => model.with_verifier(Verifier::load(&v.path)?, v.threshold.unwrap_or(0.5)),
+            None => Ok(model),
+        }
+    }
+
+    /// Sets the groups the model belongs to, see [Runner::set_group_enabled].
+    pub fn with_groups(mut self, groups: Vec<String>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Sets whether the model is run when it is first added to a [Runner].
+    pub fn with_enabled(self, enabled: bool) -> Self {
+        self.enabled
+            .store(enabled, std::sync::atomic::Ordering::SeqCst);
+        self
+    }
+
+    /// Only reports activations which verifier gives at least threshold for. The verifier
+    /// must take the same window of embeddings as the model.
+    pub fn with_verifier(
+        mut self,
+        verifier: Verifier,
+        threshold: f32,
+    ) -> Result<Self, anyhow::Error> {
+        if verifier.window != self.window {
+            anyhow::bail!(
+                "verifier takes {} embeddings, but model {} takes {}",
+                verifier.window,
+                self.name,
+                self.window
+            );
+        }
+        self.verifier = Some((verifier, threshold));
+        Ok(self)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of embeddings the model takes.
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Scores every window of embeddings in order, such as those of a recorded clip. The
+    /// verifier, if any, isn't applied.
+    pub fn scores(&self, embeddings: &[Embedding]) -> Vec<f32> {
+        embeddings
+            .windows(self.window)
+            .map(|w| {
+                let input = Tensor::from_shape(
+                    &[1, self.window, 96],
+                    &w.iter().flat_map(|e| e.iter()).copied().collect::<Vec<_>>(),
+                )
+                .unwrap();
+                self.score(&Arc::new(input))
+            })
+            .collect()
+    }
+
+    pub fn info(&self) -> ModelInfo {
+        ModelInfo {
+            name: self.name.to_string(),
+            path: self.path.clone(),
+            filters: self.filters.clone(),
+            window: self.window,
+            enabled: self.is_enabled(),
+            groups: self.groups.clone(),
+        }
+    }
+
+    /// Reads the number of embeddings a model takes from its input, which must be [1, N, 96].
+    fn input_window(model: &TypedModel) -> Result<usize, anyhow::Error> {
+        let inputs = model.input_outlets()?.len();
+        if inputs != 1 {
+            anyhow::bail!("expected a single input, found {}", inputs);
+        }
+        let fact = model.input_fact(0)?;
+        match fact.shape.as_concrete() {
+            Some(&[1, n, 96]) if n > 0 && fact.datum_type == f32::datum_type() => Ok(n),
+            _ => anyhow::bail!(
+                "expected an input of [1, N, 96] f32 embeddings, found {:?}",
+                fact
+            ),
+        }
+    }
+
+    /// Runs the model over a [1, N, 96] window of embeddings, returning the filtered score.
+    fn score(&self, input: &Arc<Tensor>) -> f32 {
+        let score = match &self.model {
+            Classifier::Graph(model) => model
+                .run(tvec!(TValue::Const(input.clone())))
+                .unwrap()
+                .remove(0)
+                .as_slice::<f32>()
+                .unwrap()[0],
+            Classifier::Template(template, scratch) => template.score(
+                input.as_slice::<f32>().unwrap(),
+                &mut scratch.lock().unwrap(),
+            ),
+        };
+        self.filters.apply(score)
     }
 
-    fn apply(&mut self, model_val: f32) -> f32 {
-        self.filters.apply(model_val)
+    /// Scores the input, after which it is checked by the verifier.
+    fn run(&self, input: &Arc<Tensor>) -> f32 {
+        self.verify(input, self.score(input))
+    }
+
+    /// Returns score unless the verifier rejects the input.
+    fn verify(&self, input: &Tensor, score: f32) -> f32 {
+        match &self.verifier {
+            Some((verifier, threshold))
+                if score > 0.
+                    && verifier.probability(input.as_slice::<f32>().unwrap()) < *threshold =>
+            {
+                0.
+            }
+            _ => score,
+        }
+    }
+
+    /// The graph of the model, unless it is a template.
+    fn graph(&self) -> Option<&TypedModel> {
+        match &self.model {
+            Classifier::Graph(model) => Some(model.model()),
+            Classifier::Template(..) => None,
+        }
+    }
+
+    /// Whether the model is a graph taking a single [1, 16, 96] input, so it can be fused
+    /// with others.
+    fn fusable(&self) -> bool {
+        self.window == NUM_EMBEDDINGS
+            && self
+                .graph()
+                .is_some_and(|g| g.output_outlets().is_ok_and(|o| !o.is_empty()))
     }
 }
 
+/// The number of embeddings openWakeWord classifiers usually take. Models with other
+/// input windows are supported, see [ModelInfo::window].
 pub const NUM_EMBEDDINGS: usize = 16;
 
+/// FusedModels computes the scores of several models with a single plan, built by wiring
+/// the graph of each model into one graph which shares their input.
+struct FusedModels {
+    /// Every model loaded when the plan was built, so changes can be detected.
+    models: Vec<Arc<NamedModel>>,
+    /// The models in the plan, in the order of its outputs.
+    members: Vec<Arc<NamedModel>>,
+    plan: Option<TypedRunnableModel<TypedModel>>,
+}
+
+impl FusedModels {
+    fn new(models: &[Arc<NamedModel>]) -> Self {
+        let mut members: Vec<_> = models.iter().filter(|m| m.fusable()).cloned().collect();
+        let plan = match members.len() {
+            0 => None,
+            _ => match FusedModels::fuse(&members) {
+                Ok(plan) => Some(plan),
+                Err(e) => {
+                    println!("failed fusing models, running them separately: {:?}", e);
+                    members.clear();
+                    None
+                }
+            },
+        };
+
+        Self {
+            models: models.to_vec(),
+            members,
+            plan,
+        }
+    }
+
+    fn fuse(members: &[Arc<NamedModel>]) -> TractResult<TypedRunnableModel<TypedModel>> {
+        let mut fused = TypedModel::default();
+        let input = fused.add_source("input", f32::fact([1, NUM_EMBEDDINGS, 96]))?;
+        let mut outputs = Vec::with_capacity(members.len());
+
+        for (i, m) in members.iter().enumerate() {
+            let model = m.graph().unwrap();
+            let source = model.input_outlets()?[0];
+            let mut mapping = HashMap::new();
+            mapping.insert(source, input);
+
+            for id in model.eval_order()? {
+                if id == source.node {
+                    continue;
+                }
+                let node = model.node(id);
+                let new = fused.add_node(
+                    format!("{}.{}", i, node.name),
+                    node.op.clone(),
+                    node.outputs.iter().map(|o| o.fact.clone()).collect(),
+                )?;
+                for (slot, outlet) in node.inputs.iter().enumerate() {
+                    fused.add_edge(mapping[outlet], InletId::new(new, slot))?;
+                }
+                for slot in 0..node.outputs.len() {
+                    mapping.insert(OutletId::new(id, slot), OutletId::new(new, slot));
+                }
+            }
+            outputs.push(mapping[&model.output_outlets()?[0]]);
+        }
+
+        fused.set_output_outlets(&outputs)?;
+        fused.into_runnable()
+    }
+
+    /// Whether the plan was built from exactly these models.
+    fn is_current(&self, models: &[Arc<NamedModel>]) -> bool {
+        self.models.len() == models.len()
+            && self
+                .models
+                .iter()
+                .zip(models.iter())
+                .all(|(a, b)| Arc::ptr_eq(a, b))
+    }
+
+    fn contains(&self, model: &Arc<NamedModel>) -> bool {
+        self.members.iter().any(|m| Arc::ptr_eq(m, model))
+    }
+
+    /// Runs every model in the plan, adding the scores of those which are enabled.
+    fn run(&self, input: &Arc<Tensor>, results: &mut Activations) {
+        let Some(plan) = &self.plan else {
+            return;
+        };
+        if !self.members.iter().any(|m| m.is_enabled()) {
+            return;
+        }
+
+        let outs = plan.run(tvec!(TValue::Const(input.clone()))).unwrap();
+        for (m, out) in self.members.iter().zip(outs.iter()) {
+            if m.is_enabled() {
+                let score = m.filters.apply(out.as_slice::<f32>().unwrap()[0]);
+                results.push((m.name.clone(), m.verify(input, score)));
+            }
+        }
+    }
+}
+
+/// The score of each enabled model for a frame.
+pub type Activations = Vec<(Arc<str>, f32)>;
+
 /// Runner computes watch-word activations over embeddings.
+///
+/// Models are run one after another, or concurrently on a pool of threads when
+/// [RunnerConfig::threads] is more than one. The list of models is only locked while it
+/// is copied at the start of each frame, so models can be changed while others run.
+///
+/// With [RunnerConfig::fused], models sharing the usual [1, 16, 96] input are merged into
+/// one plan which is rebuilt whenever models are added or removed. Fused models are all
+/// computed while any of them is enabled, so this works best without gating.
+///
+/// With [RunnerConfig::gating], models which are only used in later stages of chains are
+/// disabled until the matcher is waiting for them, see [Runner::gate].
 pub struct Runner {
-    models: Arc<Mutex<Vec<NamedModel>>>,
-    recv: Option<Receiver<Vec<(String, f32)>>>,
+    models: Arc<Mutex<Vec<Arc<NamedModel>>>>,
+    gating: bool,
+    gate: Option<Gate>,
+    recv: Option<Receiver<Activations>>,
+    recycle: SyncSender<Activations>,
     shutdown: Arc<AtomicBool>,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Runner {
     pub fn start(embeddings: Receiver<Embedding>) -> Result<Self, anyhow::Error> {
+        Self::start_with_config(embeddings, &RunnerConfig::default())
+    }
+
+    /// Starts a runner with the threads, gating and fusing set by config.
+    pub fn start_with_config(
+        embeddings: Receiver<Embedding>,
+        config: &RunnerConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let threads = config
+            .threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        let pool = match threads {
+            1 => None,
+            n => Some(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(n)
+                    .thread_name(|i| format!("runner-{}", i))
+                    .build()?,
+            ),
+        };
+        let fuse = config.fused;
         let models = Arc::new(Mutex::new(vec![]));
         let (send, recv) = sync_channel(1);
+        // Bounded, as an unbounded channel allocates as messages pass through it.
+        let (recycle, recycled) = sync_channel(2);
         let shutdown = Arc::new(AtomicBool::new(false));
 
         let models2 = models.clone();
         let shutdown2 = shutdown.clone();
         let thread = Some(thread::spawn(move || {
-            Runner::mainloop(send, models2, shutdown2, embeddings);
+            Runner::mainloop(send, recycled, models2, shutdown2, embeddings, pool, fuse);
         }));
 
         let out = Self {
             models,
+            gating: config.gating,
+            gate: None,
+            recycle,
             shutdown,
             thread,
             recv: Some(recv),
@@ -91,23 +485,148 @@ impl Runner {
         Ok(out)
     }
 
-    pub fn take_receiver(&mut self) -> Option<Receiver<Vec<(String, f32)>>> {
+    pub fn take_receiver(&mut self) -> Option<Receiver<Activations>> {
         self.recv.take()
     }
 
+    /// Returns a set of results once it has been consumed, so its buffer can be reused
+    /// for a later frame rather than allocating a new one.
+    pub fn recycle(&self, results: Activations) {
+        self.recycle.try_send(results).ok();
+    }
+
     pub fn add_model(&mut self, model: NamedModel) {
-        let models: &mut Vec<NamedModel> = &mut self.models.lock().unwrap();
+        // A replaced model keeps its configured state until the runner is gated again.
+        if let Some(gate) = &mut self.gate {
+            gate.forget(&model.name);
+        }
+        let models: &mut Vec<Arc<NamedModel>> = &mut self.models.lock().unwrap();
         models.retain(|m| m.name != model.name);
-        models.push(model);
+        models.push(Arc::new(model));
+    }
+
+    /// Removes the named model, returning whether it was loaded.
+    pub fn remove_model(&mut self, name: &str) -> bool {
+        if let Some(gate) = &mut self.gate {
+            gate.forget(name);
+        }
+        let models: &mut Vec<Arc<NamedModel>> = &mut self.models.lock().unwrap();
+        let len = models.len();
+        models.retain(|m| &*m.name != name);
+        models.len() != len
+    }
+
+    /// Enables or disables the named model, returning whether it was found. Disabled
+    /// models are not run, and are left out of the results.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        let models = self.models.lock().unwrap();
+        match models.iter().find(|m| &*m.name == name) {
+            Some(m) => {
+                m.enabled
+                    .store(enabled, std::sync::atomic::Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Enables or disables every model in the group, returning how many there were.
+    pub fn set_group_enabled(&mut self, group: &str, enabled: bool) -> usize {
+        let models = self.models.lock().unwrap();
+        let mut n = 0;
+        for m in models
+            .iter()
+            .filter(|m| m.groups.iter().any(|g| g == group))
+        {
+            m.enabled
+                .store(enabled, std::sync::atomic::Ordering::SeqCst);
+            n += 1;
+        }
+        n
+    }
+
+    /// Gates the models of the matcher's rules when [RunnerConfig::gating] is set: those
+    /// which can only match a later stage are disabled until [Runner::update_gate] finds
+    /// the matcher waiting for them. Models which are already disabled, and those in
+    /// except, are left alone. Models gated before are enabled again first, so this is
+    /// called again whenever the matcher or the models change.
+    pub fn gate(&mut self, matcher: &Matcher, except: &[String]) {
+        if let Some(gate) = self.gate.take() {
+            for m in gate.gated.iter() {
+                self.set_enabled(m, true);
+            }
+        }
+        if !self.gating {
+            return;
+        }
+        let mut gated = matcher.gated_models();
+        gated.retain(|m| !except.contains(m));
+        {
+            let models = self.models.lock().unwrap();
+            gated.retain(|g| models.iter().any(|m| &*m.name == g && m.is_enabled()));
+        }
+        for m in gated.iter() {
+            self.set_enabled(m, false);
+        }
+        println!("gating {} model(s): {:?}", gated.len(), gated);
+        self.gate = Some(Gate {
+            gated,
+            enabled: BTreeSet::new(),
+        });
+    }
+
+    /// Enables the gated models which the matcher is waiting for, and disables the rest.
+    /// Models enabled here start contributing results from the next frame.
+    pub fn update_gate(&mut self, matcher: &Matcher) {
+        let Some(gate) = &mut self.gate else {
+            return;
+        };
+        let awaited: BTreeSet<String> = matcher
+            .awaited_models()
+            .intersection(&gate.gated)
+            .cloned()
+            .collect();
+        if awaited == gate.enabled {
+            return;
+        }
+        let disable: Vec<String> = gate.enabled.difference(&awaited).cloned().collect();
+        let enable: Vec<String> = awaited.difference(&gate.enabled).cloned().collect();
+        gate.enabled = awaited;
+        for m in disable.iter() {
+            self.set_enabled(m, false);
+        }
+        for m in enable.iter() {
+            self.set_enabled(m, true);
+        }
+    }
+
+    /// Lists the loaded models, in the order they are run.
+    pub fn models(&self) -> Vec<ModelInfo> {
+        self.models
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| m.info())
+            .collect()
     }
 
     fn mainloop(
-        tx: SyncSender<Vec<(String, f32)>>,
-        models: Arc<Mutex<Vec<NamedModel>>>,
+        tx: SyncSender<Activations>,
+        recycled: Receiver<Activations>,
+        models: Arc<Mutex<Vec<Arc<NamedModel>>>>,
         shutdown: Arc<AtomicBool>,
         embs: Receiver<Embedding>,
+        pool: Option<rayon::ThreadPool>,
+        fuse: bool,
     ) {
-        let mut embeddings = CircularBuffer::<NUM_EMBEDDINGS, Embedding>::new();
+        // Holds enough embeddings for the model with the longest input window.
+        let mut embeddings: VecDeque<Embedding> = VecDeque::with_capacity(NUM_EMBEDDINGS);
+        // The input to models with each window size, which is [1, N, 96]. They are shared
+        // with each model rather than copied, and refilled in place every frame.
+        let mut inputs: BTreeMap<usize, Arc<Tensor>> = BTreeMap::new();
+        let mut snapshot: Vec<Arc<NamedModel>> = Vec::new();
+        let mut fused: Option<FusedModels> = None;
+        let mut separate: Activations = Vec::new();
 
         loop {
             if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
@@ -121,39 +640,74 @@ impl Runner {
                 return;
             }
 
-            // Don't compute activations unless we have a full set of input (16 embeddings)
-            if !embeddings.is_full() {
+            // Snapshot the models, so the lock isn't held while they run.
+            snapshot.clear();
+            snapshot.extend(models.lock().unwrap().iter().cloned());
+
+            let longest = snapshot
+                .iter()
+                .map(|m| m.window)
+                .max()
+                .unwrap_or(0)
+                .max(NUM_EMBEDDINGS);
+            while embeddings.len() > longest {
+                embeddings.pop_front();
+            }
+
+            // Don't compute activations until we have a full set of input for some model
+            // (usually 16 embeddings). Models with longer windows join in once there is
+            // enough history for them.
+            let available = embeddings.len();
+            if available < NUM_EMBEDDINGS && !snapshot.iter().any(|m| m.window <= available) {
                 continue;
             }
-            // Build a tensor that will be the input to the feature model, which is [1, 16, 96].
-            let feature_input = Tensor::from_shape(
-                &[1, NUM_EMBEDDINGS, 96],
-                embeddings
-                    .iter()
-                    .flat_map(|spect| spect.iter())
-                    .copied()
-                    .collect::<Vec<_>>()
-                    .as_slice(),
-            )
-            .unwrap();
-            let results = models
-                .lock()
-                .unwrap()
-                .iter_mut()
-                .map(|m| {
-                    (
-                        m.name.clone(),
-                        m.apply(
-                            m.model
-                                .run(tvec!(TValue::from(feature_input.clone())))
-                                .unwrap()
-                                .remove(0)
-                                .as_slice()
-                                .unwrap()[0],
-                        ),
+            snapshot.retain(|m| m.window <= available);
+
+            for m in snapshot.iter() {
+                if inputs.contains_key(&m.window) {
+                    continue;
+                }
+                let input = Tensor::zero::<f32>(&[1, m.window, 96]).unwrap();
+                inputs.insert(m.window, Arc::new(input));
+            }
+            for (window, input) in inputs.iter_mut().filter(|(w, _)| **w <= available) {
+                // Models drop their references to the input once they have run, so this
+                // doesn't copy.
+                Arc::make_mut(input)
+                    .as_slice_mut::<f32>()
+                    .unwrap()
+                    .iter_mut()
+                    .zip(
+                        embeddings
+                            .iter()
+                            .skip(available - window)
+                            .flat_map(|e| e.iter()),
                     )
-                })
-                .collect();
+                    .for_each(|(dst, src)| *dst = *src);
+            }
+
+            let mut results = recycled.try_recv().unwrap_or_default();
+            results.clear();
+            if fuse && let Some(input) = inputs.get(&NUM_EMBEDDINGS) {
+                if !fused.as_ref().is_some_and(|f| f.is_current(&snapshot)) {
+                    fused = Some(FusedModels::new(&snapshot));
+                }
+                if let Some(f) = &fused {
+                    f.run(input, &mut results);
+                    snapshot.retain(|m| !f.contains(m));
+                }
+            }
+
+            // Run the remaining enabled models separately.
+            snapshot.retain(|m| m.is_enabled());
+            let run = |m: &Arc<NamedModel>| (m.name.clone(), m.run(&inputs[&m.window]));
+            match &pool {
+                Some(pool) => {
+                    pool.install(|| snapshot.par_iter().map(run).collect_into_vec(&mut separate));
+                    results.append(&mut separate);
+                }
+                None => results.extend(snapshot.iter().map(run)),
+            }
 
             if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
                 return;
@@ -167,6 +721,19 @@ impl Runner {
     }
 }
 
+/// The models a [Runner] has disabled until the matcher waits for them.
+struct Gate {
+    gated: BTreeSet<String>,
+    enabled: BTreeSet<String>,
+}
+
+impl Gate {
+    fn forget(&mut self, name: &str) {
+        self.gated.remove(name);
+        self.enabled.remove(name);
+    }
+}
+
 impl Drop for Runner {
     fn drop(&mut self) {
         self.shutdown
@@ -176,3 +743,425 @@ impl Drop for Runner {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Embeddings which vary from one to the next, so models score each differently.
+    fn embedding(i: usize) -> Embedding {
+        let mut values = [0f32; 96];
+        for (j, v) in values.iter_mut().enumerate() {
+            *v = ((i * 96 + j) as f32 * 0.37).sin() * 2.;
+        }
+        Embedding::from(values)
+    }
+
+    fn model(name: &str, groups: &[&str]) -> NamedModel {
+        NamedModel::new(name.to_string(), format!("models/{}.onnx", name), 1.)
+            .unwrap()
+            .with_groups(groups.iter().map(|g| g.to_string()).collect())
+    }
+
+    /// Feeds a runner embeddings one frame at a time.
+    struct Harness {
+        // Dropped before the runner, so its thread stops waiting to receive or send.
+        embeddings: SyncSender<Embedding>,
+        results: Receiver<Activations>,
+        sent: usize,
+        runner: Runner,
+    }
+
+    impl Harness {
+        fn new(config: &RunnerConfig, models: Vec<NamedModel>) -> Self {
+            let (embeddings, recv) = sync_channel(NUM_EMBEDDINGS);
+            let first = models.iter().map(|m| m.window).min().unwrap_or(0);
+            let mut runner = Runner::start_with_config(recv, config).unwrap();
+            for m in models {
+                runner.add_model(m);
+            }
+            let results = runner.take_receiver().unwrap();
+            let mut out = Self {
+                runner,
+                embeddings,
+                results,
+                sent: 0,
+            };
+            // All but the last embedding of the first window, which produce no results.
+            for _ in 1..first.min(NUM_EMBEDDINGS) {
+                out.send();
+            }
+            out
+        }
+
+        fn send(&mut self) {
+            self.embeddings.send(embedding(self.sent)).unwrap();
+            self.sent += 1;
+        }
+
+        /// Sends the next embedding, returning the score of each model for the frame.
+        fn frame(&mut self) -> BTreeMap<String, f32> {
+            self.send();
+            let results = self.results.recv_timeout(Duration::from_secs(10)).unwrap();
+            results.iter().map(|(n, s)| (n.to_string(), *s)).collect()
+        }
+
+        /// The names of the models with results for the next frame.
+        fn frame_models(&mut self) -> Vec<String> {
+            self.frame().into_keys().collect()
+        }
+    }
+
+    #[test]
+    fn loads_models_from_bytes() {
+        let bytes = std::fs::read("models/hey_rhasspy_v0.1.onnx").unwrap();
+        let name = String::from("hey_rhasspy");
+        let model =
+            NamedModel::from_source(name, bytes.as_slice().into(), "in memory", 1.).unwrap();
+        assert_eq!(model.name(), "hey_rhasspy");
+        assert_eq!(model.info().path, "in memory");
+        assert_eq!(model.window(), NUM_EMBEDDINGS);
+    }
+
+    /// A classifier scoring the sigmoid of the sum of a window of embeddings.
+    fn summing(name: &str, window: usize) -> NamedModel {
+        use tract_onnx::tract_core::ops::nn::{Reduce, Reducer, sigmoid};
+        let mut model = TypedModel::default();
+        let input = model
+            .add_source("input", f32::fact([1, window, 96]))
+            .unwrap();
+        let reduce = Reduce {
+            axes: tvec![1, 2],
+            reducer: Reducer::Sum,
+        };
+        let sum = model.wire_node("sum", reduce, &[input]).unwrap();
+        let score = model.wire_node("sigmoid", sigmoid(), &sum).unwrap();
+        model.set_output_outlets(&score).unwrap();
+        NamedModel::from_typed(name.to_string(), model, "in memory".to_string(), 1.).unwrap()
+    }
+
+    /// Whether two sets of frames have the same models, with scores equal up to rounding.
+    fn same_scores(a: &[BTreeMap<String, f32>], b: &[BTreeMap<String, f32>]) -> bool {
+        a.len() == b.len()
+            && a.iter().zip(b.iter()).all(|(a, b)| {
+                a.keys().eq(b.keys())
+                    && a.values()
+                        .zip(b.values())
+                        .all(|(a, b)| (a - b).abs() < 1e-5)
+            })
+    }
+
+    #[test]
+    fn fused_models_score_as_separate_models() {
+        let names = [
+            "daytime",
+            "nighttime",
+            "turn_on",
+            "turn_off",
+            "hey_rhasspy_v0.1",
+        ];
+        let fused = RunnerConfig {
+            fused: true,
+            ..Default::default()
+        };
+        let expected = run_frames(&RunnerConfig::default(), &names);
+        assert!(same_scores(&run_frames(&fused, &names), &expected));
+    }
+
+    #[test]
+    fn models_with_other_inputs_run_beside_fused_models() {
+        let models = || {
+            vec![
+                model("turn_on", &[]),
+                summing("short", 8),
+                summing("sum", NUM_EMBEDDINGS),
+                summing("long", 24),
+            ]
+        };
+        let fused = RunnerConfig {
+            fused: true,
+            ..Default::default()
+        };
+        let frames = |config: &RunnerConfig| {
+            let mut h = Harness::new(config, models());
+            (0..20).map(|_| h.frame()).collect::<Vec<_>>()
+        };
+        // Only the models taking the usual input are fused.
+        let loaded: Vec<_> = models().into_iter().map(Arc::new).collect();
+        let members: Vec<_> = FusedModels::new(&loaded)
+            .members
+            .iter()
+            .map(|m| m.name().to_string())
+            .collect();
+        assert_eq!(members, ["turn_on", "sum"]);
+
+        let expected = frames(&RunnerConfig::default());
+        let actual = frames(&fused);
+        assert!(same_scores(&actual, &expected));
+        // Models join in once there are enough embeddings for them.
+        assert_eq!(actual[0].keys().collect::<Vec<_>>(), ["short"]);
+        assert_eq!(actual[8].len(), 3);
+        assert_eq!(actual[16].len(), 4);
+    }
+
+    #[test]
+    fn models_take_windows_other_than_16() {
+        let model = summing("long", 24);
+        assert_eq!(model.window(), 24);
+        assert_eq!(model.info().window, 24);
+
+        let embeddings: Vec<_> = (0..30).map(embedding).collect();
+        let scores = model.scores(&embeddings);
+        assert_eq!(scores.len(), 7);
+        let sum: f32 = embeddings[..24].iter().flat_map(|e| e.iter()).sum();
+        assert!((scores[0] - 1. / (1. + (-sum).exp())).abs() < 1e-5);
+
+        // The runner scores the latest 24 embeddings once it has them, giving empty
+        // results until then.
+        let mut h = Harness::new(&RunnerConfig::default(), vec![summing("long", 24)]);
+        for _ in NUM_EMBEDDINGS..24 {
+            assert!(h.frame().is_empty());
+        }
+        assert!((h.frame()["long"] - scores[0]).abs() < 1e-5);
+        assert!((h.frame()["long"] - scores[1]).abs() < 1e-5);
+    }
+
+    /// The error loading a graph which takes the given inputs and scores the first.
+    fn rejection(inputs: &[TypedFact]) -> String {
+        let mut model = TypedModel::default();
+        let sources: Vec<_> = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, fact)| {
+                model
+                    .add_source(format!("input{}", i), fact.clone())
+                    .unwrap()
+            })
+            .collect();
+        let sigmoid = tract_onnx::tract_core::ops::nn::sigmoid();
+        let score = model.wire_node("sigmoid", sigmoid, &sources[..1]).unwrap();
+        model.set_output_outlets(&score).unwrap();
+        match NamedModel::from_typed("m".to_string(), model, "in memory".to_string(), 1.) {
+            Ok(_) => panic!("loaded a model taking {:?}", inputs),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn models_need_a_single_window_of_embeddings() {
+        let n = TDim::Sym(SymbolScope::default().sym("N"));
+        for fact in [
+            f32::fact([1, 16, 32]),
+            f32::fact([2, 16, 96]),
+            f32::fact([1, 0, 96]),
+            f32::fact([1.into(), n, 96.into()]),
+            f64::fact([1, 16, 96]),
+        ] {
+            let error = rejection(&[fact]);
+            assert!(
+                error.starts_with("in memory: expected an input of"),
+                "{}",
+                error
+            );
+        }
+        let two = [f32::fact([1, 16, 96]), f32::fact([1, 16, 96])];
+        assert_eq!(
+            rejection(&two),
+            "in memory: expected a single input, found 2"
+        );
+    }
+
+    #[test]
+    fn verifier_only_gates_activations() {
+        let model = NamedModel::new("m", "models/hey_rhasspy_v0.1.onnx", 1.).unwrap();
+        let input = Tensor::zero::<f32>(&[1, NUM_EMBEDDINGS, 96]).unwrap();
+        // Without weights, the verifier gives every input a probability of 0.5.
+        let verifier = Verifier {
+            window: NUM_EMBEDDINGS,
+            weights: vec![0.; NUM_EMBEDDINGS * 96],
+            bias: 0.,
+        };
+        let accepting = model.with_verifier(verifier.clone(), 0.5).unwrap();
+        assert_eq!(accepting.verify(&input, 0.8), 0.8);
+        assert_eq!(accepting.verify(&input, 0.), 0.);
+
+        let model = NamedModel::new("m", "models/hey_rhasspy_v0.1.onnx", 1.).unwrap();
+        let rejecting = model.with_verifier(verifier, 0.6).unwrap();
+        assert_eq!(rejecting.verify(&input, 0.8), 0.);
+        assert_eq!(rejecting.verify(&input, 0.01), 0.);
+        assert_eq!(rejecting.verify(&input, 0.), 0.);
+
+        let wrong_window = Verifier {
+            window: 4,
+            weights: vec![0.; 4 * 96],
+            bias: 0.,
+        };
+        let model = NamedModel::new("m", "models/hey_rhasspy_v0.1.onnx", 1.).unwrap();
+        assert!(model.with_verifier(wrong_window, 0.5).is_err());
+    }
+
+    #[test]
+    fn disabled_models_and_groups_produce_no_activations() {
+        let mut h = Harness::new(
+            &RunnerConfig::default(),
+            vec![
+                model("turn_on", &["switch"]),
+                model("turn_off", &["switch"]),
+                model("daytime", &["time"]),
+                model("nighttime", &["time"]).with_enabled(false),
+            ],
+        );
+        assert_eq!(h.frame_models(), ["daytime", "turn_off", "turn_on"]);
+
+        assert!(h.runner.set_enabled("turn_on", false));
+        assert!(!h.runner.set_enabled("turn_up", false));
+        assert_eq!(h.frame_models(), ["daytime", "turn_off"]);
+
+        assert_eq!(h.runner.set_group_enabled("switch", false), 2);
+        assert_eq!(h.runner.set_group_enabled("volume", false), 0);
+        assert_eq!(h.frame_models(), ["daytime"]);
+
+        // Re-enabling the group enables every model in it, including turn_on.
+        assert_eq!(h.runner.set_group_enabled("switch", true), 2);
+        assert_eq!(h.runner.set_group_enabled("time", true), 2);
+        assert_eq!(
+            h.frame_models(),
+            ["daytime", "nighttime", "turn_off", "turn_on"]
+        );
+        assert!(h.runner.models().iter().all(|m| m.enabled));
+    }
+
+    #[test]
+    fn removed_models_are_gone() {
+        let mut h = Harness::new(
+            &RunnerConfig::default(),
+            vec![model("turn_on", &[]), model("turn_off", &[])],
+        );
+        assert!(h.runner.remove_model("turn_on"));
+        assert!(!h.runner.remove_model("turn_on"));
+        let names: Vec<_> = h.runner.models().into_iter().map(|m| m.name).collect();
+        assert_eq!(names, ["turn_off"]);
+        assert_eq!(h.frame_models(), ["turn_off"]);
+        assert!(!h.runner.set_enabled("turn_on", true));
+        assert_eq!(h.frame_models(), ["turn_off"]);
+    }
+
+    /// A matcher with one rule, whose actions are sent to the returned receiver.
+    fn matcher(chain: &str) -> (Matcher, Receiver<(crate::Action, crate::ActionContext)>) {
+        let (send, fired) = std::sync::mpsc::channel();
+        let mut matcher = Matcher::new();
+        matcher.set_handler(send);
+        let rule = crate::MatchConfig {
+            chain: serde_yaml::from_str(chain).unwrap(),
+            action: "exit:0".into(),
+        };
+        matcher.add_rule("rule".to_string(), rule).unwrap();
+        (matcher, fired)
+    }
+
+    fn activate(matcher: &mut Matcher, model: &str) {
+        matcher.eval(&[(Arc::from(model), 0.9)]);
+    }
+
+    fn gating() -> RunnerConfig {
+        RunnerConfig {
+            gating: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn gating_runs_later_stages_while_they_are_awaited() {
+        let mut h = Harness::new(
+            &gating(),
+            vec![
+                model("daytime", &[]),
+                model("turn_on", &[]),
+                model("turn_off", &[]),
+                model("nighttime", &[]).with_enabled(false),
+            ],
+        );
+        let (mut matcher, fired) =
+            matcher("[{model: daytime}, {any_of: [turn_on, turn_off, nighttime]}]");
+        h.runner.gate(&matcher, &["turn_off".to_string()]);
+        assert_eq!(h.frame_models(), ["daytime", "turn_off"]);
+        h.runner.update_gate(&matcher);
+        assert_eq!(h.frame_models(), ["daytime", "turn_off"]);
+
+        // nighttime was disabled before gating, so it stays disabled.
+        activate(&mut matcher, "daytime");
+        h.runner.update_gate(&matcher);
+        assert_eq!(h.frame_models(), ["daytime", "turn_off", "turn_on"]);
+
+        activate(&mut matcher, "turn_on");
+        assert!(fired.try_recv().is_ok());
+        h.runner.update_gate(&matcher);
+        assert_eq!(h.frame_models(), ["daytime", "turn_off"]);
+
+        // Gating again releases the models gated before.
+        h.runner.gate(&Matcher::new(), &[]);
+        assert_eq!(h.frame_models(), ["daytime", "turn_off", "turn_on"]);
+    }
+
+    #[test]
+    fn replaced_models_are_no_longer_gated() {
+        let mut h = Harness::new(
+            &gating(),
+            vec![model("daytime", &[]), model("turn_on", &[])],
+        );
+        let (matcher, _fired) = matcher("[{model: daytime}, {model: turn_on}]");
+        h.runner.gate(&matcher, &[]);
+        assert_eq!(h.frame_models(), ["daytime"]);
+
+        h.runner.add_model(model("turn_on", &[]));
+        h.runner.update_gate(&matcher);
+        assert_eq!(h.frame_models(), ["daytime", "turn_on"]);
+
+        h.runner.gate(&matcher, &[]);
+        assert_eq!(h.frame_models(), ["daytime"]);
+    }
+
+    #[test]
+    fn gating_is_off_by_default() {
+        let mut h = Harness::new(
+            &RunnerConfig::default(),
+            vec![model("daytime", &[]), model("turn_on", &[])],
+        );
+        let (matcher, _fired) = matcher("[{model: daytime}, {model: turn_on}]");
+        h.runner.gate(&matcher, &[]);
+        h.runner.update_gate(&matcher);
+        assert_eq!(h.frame_models(), ["daytime", "turn_on"]);
+    }
+
+    /// Runs the models for a few frames, returning the scores of each frame.
+    fn run_frames(config: &RunnerConfig, names: &[&str]) -> Vec<BTreeMap<String, f32>> {
+        let models = names.iter().map(|n| model(n, &[])).collect();
+        let mut h = Harness::new(config, models);
+        (0..8).map(|_| h.frame()).collect()
+    }
+
+    #[test]
+    fn threads_give_identical_results() {
+        let names = [
+            "daytime",
+            "nighttime",
+            "turn_on",
+            "turn_off",
+            "hey_rhasspy_v0.1",
+        ];
+        let one = RunnerConfig {
+            threads: Some(1),
+            ..Default::default()
+        };
+        let expected = run_frames(&one, &names);
+        assert!(expected.iter().all(|f| f.len() == names.len()));
+        for n in [2, 4] {
+            let config = RunnerConfig {
+                threads: Some(n),
+                ..Default::default()
+            };
+            assert_eq!(run_frames(&config, &names), expected, "{} threads", n);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::borrow::Cow;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use tract_onnx::prelude::*;
+
+/// Where to load an ONNX, TFLite or NNEF model from: a file, bytes already in memory, or
+/// any reader.
+pub enum ModelSource<'a> {
+    Path(PathBuf),
+    Bytes(&'a [u8]),
+    Reader(Box<dyn Read + 'a>),
+}
+
+impl std::fmt::Debug for ModelSource<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelSource::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            ModelSource::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            ModelSource::Reader(_) => f.write_str("Reader"),
+        }
+    }
+}
+
+impl From<&str> for ModelSource<'_> {
+    fn from(path: &str) -> Self {
+        ModelSource::Path(path.into())
+    }
+}
+
+impl From<String> for ModelSource<'_> {
+    fn from(path: String) -> Self {
+        ModelSource::Path(path.into())
+    }
+}
+
+impl From<&Path> for ModelSource<'_> {
+    fn from(path: &Path) -> Self {
+        ModelSource::Path(path.into())
+    }
+}
+
+impl From<PathBuf> for ModelSource<'_> {
+    fn from(path: PathBuf) -> Self {
+        ModelSource::Path(path)
+    }
+}
+
+impl<'a> From<&'a [u8]> for ModelSource<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        ModelSource::Bytes(bytes)
+    }
+}
+
+#[cfg(feature = "embedded-models")]
+static MELSPECTROGRAM: &[u8] = include_bytes!("../melspectrogram.onnx");
+#[cfg(feature = "embedded-models")]
+static EMBEDDING: &[u8] = include_bytes!("../embedding_model.onnx");
+
+impl ModelSource<'static> {
+    /// The melspectrogram model used by [crate::Specter]. With the `embedded-models`
+    /// feature it is built into the binary, otherwise it is read from
+    /// `melspectrogram.onnx` in the current directory.
+    pub fn melspectrogram() -> Self {
+        #[cfg(feature = "embedded-models")]
+        return ModelSource::Bytes(MELSPECTROGRAM);
+        #[cfg(not(feature = "embedded-models"))]
+        return ModelSource::Path("melspectrogram.onnx".into());
+    }
+
+    /// The embedding model used by [crate::Embedder]. With the `embedded-models` feature
+    /// it is built into the binary, otherwise it is read from `embedding_model.onnx` in
+    /// the current directory.
+    pub fn embedding() -> Self {
+        #[cfg(feature = "embedded-models")]
+        return ModelSource::Bytes(EMBEDDING);
+        #[cfg(not(feature = "embedded-models"))]
+        return ModelSource::Path("embedding_model.onnx".into());
+    }
+}
+
+/// The formats models can be loaded from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Onnx,
+    Tflite,
+    /// tract's NNEF, as written by [crate::ModelCache].
+    Nnef,
+}
+
+impl Format {
+    /// Detects the format from the first bytes of a model. TFLite flatbuffers carry a
+    /// file identifier at bytes 4..8, and NNEF models are tar archives.
+    fn of(header: &[u8]) -> Self {
+        if header.get(4..8) == Some(b"TFL3") {
+            Format::Tflite
+        } else if header.get(257..262) == Some(b"ustar") || header.starts_with(&[0x1f, 0x8b]) {
+            Format::Nnef
+        } else {
+            Format::Onnx
+        }
+    }
+
+    /// Detects the format of a file from its extension, or failing that its first bytes.
+    fn of_file(path: &Path) -> Result<Self, anyhow::Error> {
+        let ext = path.extension().and_then(|ext| ext.to_str());
+        if ext.is_some_and(|ext| ext.eq_ignore_ascii_case("tflite")) {
+            return Ok(Format::Tflite);
+        }
+        if ext.is_some_and(|ext| ["tar", "tgz", "nnef"].contains(&ext)) || path.is_dir() {
+            return Ok(Format::Nnef);
+        }
+        let mut header = Vec::with_capacity(262);
+        std::fs::File::open(path)
+            .and_then(|f| f.take(262).read_to_end(&mut header))
+            .map_err(|e| anyhow::anyhow!("Opening {:?}: {}", path, e))?;
+        Ok(Format::of(&header))
+    }
+}
+
+/// The NNEF framework, with the tract and ONNX extensions needed for imported models.
+/// Building it parses the NNEF standard library, which takes longer than loading most
+/// models, so it is only built once.
+pub(crate) fn nnef() -> &'static tract_nnef::framework::Nnef {
+    static NNEF: OnceLock<tract_nnef::framework::Nnef> = OnceLock::new();
+    NNEF.get_or_init(|| tract_nnef::nnef().with_tract_core().with_onnx())
+}
+
+impl<'a> ModelSource<'a> {
+    /// Reads the whole model into memory.
+    pub(crate) fn read(self) -> Result<Cow<'a, [u8]>, anyhow::Error> {
+        Ok(match self {
+            ModelSource::Path(path) => Cow::Owned(
+                std::fs::read(&path).map_err(|e| anyhow::anyhow!("Opening {:?}: {}", path, e))?,
+            ),
+            ModelSource::Bytes(bytes) => Cow::Borrowed(bytes),
+            ModelSource::Reader(mut reader) => {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                Cow::Owned(bytes)
+            }
+        })
+    }
+
+    /// Loads an ONNX, TFLite or NNEF model, fixing the shape of its f32 input if
+    /// input_shape is set. The format is recognised by the file extension or magic bytes.
+    pub(crate) fn typed(self, input_shape: Option<&[usize]>) -> Result<TypedModel, anyhow::Error> {
+        match self {
+            ModelSource::Path(path) => match Format::of_file(&path)? {
+                Format::Onnx => onnx(tract_onnx::onnx().model_for_path(path)?, input_shape),
+                Format::Tflite => typed(
+                    tract_tflite::Tflite::default().model_for_path(path)?,
+                    input_shape,
+                ),
+                Format::Nnef => typed(nnef().model_for_path(path)?, input_shape),
+            },
+            ModelSource::Bytes(mut bytes) => match Format::of(bytes) {
+                Format::Onnx => onnx(tract_onnx::onnx().model_for_read(&mut bytes)?, input_shape),
+                Format::Tflite => typed(
+                    tract_tflite::Tflite::default().model_for_read(&mut bytes)?,
+                    input_shape,
+                ),
+                Format::Nnef => typed(nnef().model_for_read(&mut bytes)?, input_shape),
+            },
+            ModelSource::Reader(reader) => {
+                let bytes = ModelSource::Reader(reader).read()?;
+                ModelSource::Bytes(&bytes).typed(input_shape)
+            }
+        }
+    }
+
+    /// Loads a model as [ModelSource::typed] does, and optimizes it for running.
+    pub(crate) fn load(
+        self,
+        input_shape: Option<&[usize]>,
+    ) -> Result<TypedRunnableModel<TypedModel>, anyhow::Error> {
+        self.typed(input_shape)?.into_optimized()?.into_runnable()
+    }
+}
+
+/// Types an ONNX model, after fixing the shape of its f32 input if input_shape is set.
+fn onnx(model: InferenceModel, input_shape: Option<&[usize]>) -> Result<TypedModel, anyhow::Error> {
+    match input_shape {
+        Some(shape) => Ok(model
+            .with_input_fact(0, f32::fact(shape).into())?
+            .into_typed()?),
+        None => Ok(model.into_typed()?),
+    }
+}
+
+/// Fixes the shape of an already typed model's f32 input if input_shape is set. Setting
+/// the input fact alone would leave the facts of later nodes as they were, so symbolic
+/// dimensions are bound to their sizes and the whole model is typed again, while fixed
+/// dimensions must already match.
+fn typed(model: TypedModel, input_shape: Option<&[usize]>) -> Result<TypedModel, anyhow::Error> {
+    let Some(shape) = input_shape else {
+        return Ok(model);
+    };
+    let fact = model.input_fact(0)?;
+    if fact.rank() != shape.len() {
+        anyhow::bail!("expected an input of shape {:?}, found {:?}", shape, fact);
+    }
+    let mut values = SymbolValues::default();
+    for (dim, size) in fact.shape.iter().zip(shape) {
+        match dim {
+            TDim::Sym(sym) => values.set(sym, *size as i64),
+            dim if dim.to_i64().is_ok_and(|d| d == *size as i64) => {}
+            _ => anyhow::bail!("expected an input of shape {:?}, found {:?}", shape, fact),
+        }
+    }
+    model.concretize_dims(&values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Embedding, NamedModel};
+
+    /// The model of the openWakeWord v0.5.1 release, which isn't checked in.
+    const TFLITE: &str = "models/hey_rhasspy_v0.1.tflite";
+
+    #[test]
+    #[ignore = "needs hey_rhasspy_v0.1.tflite from the openWakeWord v0.5.1 release in models/"]
+    fn detects_tflite() {
+        assert_eq!(Format::of_file(Path::new(TFLITE)).unwrap(), Format::Tflite);
+        let bytes = std::fs::read(TFLITE).unwrap();
+        assert_eq!(Format::of(&bytes), Format::Tflite);
+    }
+
+    #[test]
+    #[ignore = "needs hey_rhasspy_v0.1.tflite from the openWakeWord v0.5.1 release in models/"]
+    fn tflite_scores_match_onnx() {
+        let embeddings: Vec<Embedding> = (0..40)
+            .map(|i| {
+                let mut values = [0.; 96];
+                for (j, v) in values.iter_mut().enumerate() {
+                    *v = (((i * 31 + j * 17) % 23) as f32 - 11.) / 3.;
+                }
+                values.into()
+            })
+            .collect();
+
+        let onnx = NamedModel::new("onnx", "models/hey_rhasspy_v0.1.onnx", 1.).unwrap();
+        let bytes = std::fs::read(TFLITE).unwrap();
+        let tflite =
+            NamedModel::from_source("tflite", bytes.as_slice().into(), TFLITE, 1.).unwrap();
+        let (a, b) = (onnx.scores(&embeddings), tflite.scores(&embeddings));
+        assert_eq!(a.len(), b.len());
+        assert!(a.iter().any(|s| *s > 0.01 && *s < 0.99), "{:?}", a);
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn input_shape_types_the_whole_model() {
+        let batch = TDim::Sym(SymbolScope::default().sym("N"));
+        let mut model = TypedModel::default();
+        let input = model
+            .add_source("input", f32::fact(&[batch, 4.into()]))
+            .unwrap();
+        let output = model
+            .wire_node(
+                "sigmoid",
+                tract_onnx::tract_core::ops::nn::sigmoid(),
+                &[input],
+            )
+            .unwrap();
+        model.set_output_outlets(&output).unwrap();
+
+        let model = typed(model, Some(&[2, 4])).unwrap();
+        assert_eq!(
+            model.output_fact(0).unwrap().shape.as_concrete(),
+            Some(&[2, 4][..])
+        );
+    }
+
+    #[test]
+    #[ignore = "needs hey_rhasspy_v0.1.tflite from the openWakeWord v0.5.1 release in models/"]
+    fn input_shape_must_match_fixed_dimensions() {
+        let fixed = ModelSource::from(TFLITE).typed(Some(&[1, 8, 96]));
+        assert!(fixed.is_err());
+    }
+}
@@ -2,50 +2,106 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
 use std::thread;
+use std::time::Duration;
 
 use circular_buffer::CircularBuffer;
 use tract_onnx::prelude::*;
 
-use crate::Chunk;
+use crate::sampler::SAMPLE_RATE;
+use crate::tee::Taps;
+use crate::{Chunk, ModelSource};
 pub const SPECTOGRAM_SAMPLES: usize = 1280;
+/// The number of melspectograms computed from each chunk of SPECTOGRAM_SAMPLES.
+pub const SPECTOGRAMS_PER_CHUNK: usize = 5;
 
+/// Melspectogram is one frame of 32 mel bands, numbered in order from the start of the
+/// stream along with the position of the audio it was computed from.
 #[derive(Default, Clone, Debug)]
-pub struct Melspectogram([f32; 32]);
+pub struct Melspectogram {
+    values: [f32; 32],
+    id: u64,
+    sample: u64,
+}
+
+/// Creates a melspectogram with id 0, ending at sample 0, for values which aren't from a
+/// stream. Use [Melspectogram::new] to place it in one.
+impl From<[f32; 32]> for Melspectogram {
+    fn from(values: [f32; 32]) -> Self {
+        Self {
+            values,
+            ..Default::default()
+        }
+    }
+}
 
 impl Melspectogram {
+    /// Creates a melspectogram with the given id, ending sample samples into the stream.
+    pub fn new(values: [f32; 32], id: u64, sample: u64) -> Self {
+        Self { values, id, sample }
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.values
+    }
+
+    /// The index of the melspectogram since the start of the stream.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The number of samples into the stream that the audio it covers ends, approximately.
+    pub fn sample(&self) -> u64 {
+        self.sample
+    }
+
+    /// The time into the stream that the audio it covers ends, as a [crate::StreamClock]
+    /// would measure it.
+    pub fn timestamp(&self) -> Duration {
+        Duration::from_micros(self.sample * 1_000_000 / SAMPLE_RATE as u64)
+    }
+
     pub fn iter_mut(&mut self) -> core::slice::IterMut<'_, f32> {
-        self.0.iter_mut()
+        self.values.iter_mut()
     }
 
     pub fn iter(&self) -> core::slice::Iter<'_, f32> {
-        self.0.iter()
+        self.values.iter()
     }
 }
 
 /// Specter collects chunks of samples and outputs its melspectogram.
 pub struct Specter {
     recv: Option<Receiver<Vec<Melspectogram>>>,
+    taps: Taps<Vec<Melspectogram>>,
     shutdown: Arc<AtomicBool>,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Specter {
     pub fn start(samples: Receiver<Chunk<SPECTOGRAM_SAMPLES>>) -> Result<Self, anyhow::Error> {
-        let spec_model = tract_onnx::onnx()
-            // load the model
-            .model_for_path("melspectrogram.onnx")?
-            .into_optimized()?
-            .into_runnable()?;
+        Self::start_with_model(samples, ModelSource::melspectrogram())
+    }
+
+    /// Starts a specter using the melspectrogram model from source.
+    pub fn start_with_model(
+        samples: Receiver<Chunk<SPECTOGRAM_SAMPLES>>,
+        source: ModelSource,
+    ) -> Result<Self, anyhow::Error> {
+        let spec_model = source.load(None)?;
 
         let (send, recv) = sync_channel(1);
         let shutdown = Arc::new(AtomicBool::new(false));
 
+        let taps = Taps::new();
+
         let shutdown2 = shutdown.clone();
+        let taps2 = taps.clone();
         let thread = Some(thread::spawn(move || {
-            Specter::mainloop(send, shutdown2, samples, spec_model);
+            Specter::mainloop(send, taps2, shutdown2, samples, spec_model);
         }));
 
         let out = Self {
+            taps,
             shutdown,
             thread,
             recv: Some(recv),
@@ -58,8 +114,16 @@ impl Specter {
         self.recv.take()
     }
 
+    /// Returns another receiver of the melspectograms, so they can be used alongside the
+    /// pipeline without being computed twice. Up to bound batches (at least one) are
+    /// buffered, after which batches are dropped until the receiver catches up.
+    pub fn tap(&self, bound: usize) -> Receiver<Vec<Melspectogram>> {
+        self.taps.add(bound)
+    }
+
     fn mainloop(
         tx: SyncSender<Vec<Melspectogram>>,
+        taps: Taps<Vec<Melspectogram>>,
         shutdown: Arc<AtomicBool>,
         samples: Receiver<Chunk<SPECTOGRAM_SAMPLES>>,
         spec_model: TypedRunnableModel<TypedModel>,
@@ -122,30 +186,43 @@ impl Specter {
                         + 0.25 * co_effs[SPECTOGRAM_SAMPLES - i - 1] * after.unwrap_or(&0.);
                 });
 
+            // Each melspectogram covers an equal part of the chunk being computed.
+            let chunk_id = buffers.get(1).unwrap().id;
+            let hop = (SPECTOGRAM_SAMPLES / SPECTOGRAMS_PER_CHUNK) as u64;
+
             let samples = Tensor::from_shape(&[1, SPECTOGRAM_SAMPLES], &s).unwrap();
 
             // run the spectogram on the input
             let out = spec_model.run(tvec!(samples.into())).unwrap().remove(0);
 
-            // so the spectogram output is [1, 1, 5, 32] but we only care about each 32-float sequence,
-            // each of which represents a spectogram. Lets iterate in those chunks and add it to our buffer.
-            let mut spects: Vec<Melspectogram> = Vec::with_capacity(5);
-            spects.extend(out.as_slice::<f32>().unwrap().chunks(32).map(|chunk| {
-                let mut out = Melspectogram::default();
-                chunk
-                    .iter()
-                    .zip(out.iter_mut())
-                    .for_each(|(input, output)| {
-                        // Don't h8 this is what openWakeWords does! https://github.com/dscripka/openWakeWord/blob/main/openwakeword/utils.py#L180
-                        // ¯\_(ツ)_/¯  ¯\_(ツ)_/¯  ¯\_(ツ)_/¯  ¯\_(ツ)_/¯
-                        *output = *input / 10.0 + 2.0;
-                    });
-                out
-            }));
+            // so the spectogram output is [1, 1, 5, 32] but we only care about each 32-float
+            // sequence, each of which represents a spectogram. Lets iterate in those chunks
+            // and add it to our buffer.
+            let mut spects: Vec<Melspectogram> = Vec::with_capacity(SPECTOGRAMS_PER_CHUNK);
+            spects.extend(out.as_slice::<f32>().unwrap().chunks(32).enumerate().map(
+                |(i, chunk)| {
+                    let mut out = Melspectogram::new(
+                        [0.; 32],
+                        chunk_id * SPECTOGRAMS_PER_CHUNK as u64 + i as u64,
+                        chunk_id * SPECTOGRAM_SAMPLES as u64 + (i as u64 + 1) * hop,
+                    );
+                    chunk
+                        .iter()
+                        .zip(out.iter_mut())
+                        .for_each(|(input, output)| {
+                            // Don't h8 this is what openWakeWords does!
+                            // https://github.com/dscripka/openWakeWord/blob/main/openwakeword/utils.py#L180
+                            // ¯\_(ツ)_/¯  ¯\_(ツ)_/¯  ¯\_(ツ)_/¯  ¯\_(ツ)_/¯
+                            *output = *input / 10.0 + 2.0;
+                        });
+                    out
+                },
+            ));
             if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
                 return;
             }
 
+            taps.send(&spects);
             if let Err(e) = tx.send(spects) {
                 println!("failed send, specter thread shutting down! {:?}", e);
                 return;
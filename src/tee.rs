@@ -1,6 +1,6 @@
-use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
-use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 use crate::Chunk;
@@ -77,3 +77,52 @@ impl<const S: usize, const N: usize> Drop for Tee<S, N> {
         }
     }
 }
+
+/// Taps sends copies of a stage's output to receivers added while it runs, so features
+/// can be observed without changing the pipeline. Unlike [Tee], a tap which falls behind
+/// misses values rather than holding up the stage, and is forgotten once dropped.
+#[derive(Clone)]
+pub(crate) struct Taps<T>(Arc<Mutex<Vec<SyncSender<T>>>>);
+
+impl<T: Clone> Taps<T> {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(Mutex::new(Vec::new())))
+    }
+
+    /// Adds a receiver, which buffers up to bound values. A bound of 0 is treated as 1,
+    /// as a rendezvous channel would miss every value sent while it isn't waiting.
+    pub(crate) fn add(&self, bound: usize) -> Receiver<T> {
+        let (send, recv) = sync_channel(bound.max(1));
+        self.0.lock().unwrap().push(send);
+        recv
+    }
+
+    pub(crate) fn send(&self, value: &T) {
+        let mut taps = self.0.lock().unwrap();
+        taps.retain(|tx| {
+            !matches!(
+                tx.try_send(value.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taps_buffer_at_least_one_value() {
+        let taps = Taps::new();
+        let recv = taps.add(0);
+        taps.send(&1);
+        taps.send(&2);
+        assert_eq!(recv.try_iter().collect::<Vec<_>>(), vec![1]);
+
+        // Dropped receivers are forgotten.
+        drop(recv);
+        taps.send(&3);
+        assert!(taps.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Embedding, NUM_EMBEDDINGS};
+
+/// Template detects a phrase by comparing the latest embeddings with a few recorded
+/// examples of it, so wake words can be added without training a classifier. See the
+/// `enroll` subcommand.
+///
+/// Each example is aligned with the end of the window of embeddings using dynamic time
+/// warping, so the phrase may be spoken up to twice as fast or slow as when it was
+/// recorded. The cost of aligning two embeddings is their cosine distance, and the
+/// distance to an example is the mean cost of the best alignment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Template {
+    /// The embeddings of each example, up to [NUM_EMBEDDINGS] each, ending as the phrase
+    /// ends.
+    pub examples: Vec<Vec<Vec<f32>>>,
+    /// The distance to the closest example at which the score is 0.5. Scores fall from 1
+    /// at a distance of 0 to 0 at twice the threshold.
+    pub threshold: f32,
+}
+
+/// Buffers reused by [Template::score] from one frame to the next, so scoring doesn't
+/// allocate.
+#[derive(Debug, Default)]
+pub(crate) struct Scratch {
+    /// The window being scored, normalized and flattened.
+    window: Vec<f32>,
+    rows: Rows,
+}
+
+/// The last two rows of the cost matrix filled by [dtw].
+#[derive(Debug, Default)]
+struct Rows {
+    prev: Vec<f32>,
+    cur: Vec<f32>,
+}
+
+/// Scales an embedding to unit length.
+fn normalize(embedding: &mut [f32]) {
+    let norm = embedding
+        .iter()
+        .map(|v| v * v)
+        .sum::<f32>()
+        .sqrt()
+        .max(1e-6);
+    embedding.iter_mut().for_each(|v| *v /= norm);
+}
+
+fn normalized<'a, I: IntoIterator<Item = &'a f32>>(embedding: I) -> Vec<f32> {
+    let mut out: Vec<f32> = embedding.into_iter().copied().collect();
+    normalize(&mut out);
+    out
+}
+
+fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    1. - a.iter().zip(b.iter()).map(|(a, b)| a * b).sum::<f32>()
+}
+
+/// Aligns example with the end of window, returning the mean cosine distance of the best
+/// alignment. Both are normalized embeddings, those of the window flattened. Each
+/// embedding of the example is matched with the same embedding of the window as the one
+/// before it, or the next or the one after that, so the example can start anywhere but
+/// must end with the window.
+fn dtw(example: &[Vec<f32>], window: &[f32], rows: &mut Rows) -> f32 {
+    let Rows { prev, cur } = rows;
+    prev.clear();
+    prev.extend(window.chunks(96).map(|w| cosine_distance(&example[0], w)));
+    cur.resize(prev.len(), 0.);
+    for e in example.iter().skip(1) {
+        for (j, w) in window.chunks(96).enumerate() {
+            let best = (j.saturating_sub(2)..=j)
+                .map(|k| prev[k])
+                .fold(f32::INFINITY, f32::min);
+            cur[j] = best + cosine_distance(e, w);
+        }
+        std::mem::swap(prev, cur);
+    }
+    prev[prev.len() - 1] / example.len() as f32
+}
+
+impl Template {
+    /// Whether a model path refers to a template, rather than a classifier. Templates are
+    /// JSON, but are saved with a `.template` extension so they aren't mistaken for the
+    /// JSON verifiers are saved as.
+    pub fn is_template(path: &str) -> bool {
+        Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("template"))
+    }
+
+    /// Builds a template from the embeddings of each example, keeping the last
+    /// [NUM_EMBEDDINGS] of each.
+    pub fn new(examples: &[Vec<Embedding>], threshold: f32) -> Self {
+        Self {
+            examples: examples
+                .iter()
+                .map(|e| {
+                    e.iter()
+                        .skip(e.len().saturating_sub(NUM_EMBEDDINGS))
+                        .map(|e| e.iter().copied().collect())
+                        .collect()
+                })
+                .collect(),
+            threshold,
+        }
+    }
+
+    /// Reads a template saved as JSON.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let f =
+            std::fs::File::open(path).map_err(|e| anyhow::anyhow!("opening {:?}: {}", path, e))?;
+        let template: Template = serde_json::from_reader(std::io::BufReader::new(f))
+            .map_err(|e| anyhow::anyhow!("reading {:?}: {}", path, e))?;
+        if template.examples.is_empty() || template.examples.iter().any(|e| e.is_empty()) {
+            anyhow::bail!("{:?}: examples must not be empty", path);
+        }
+        if let Some(e) = template.examples.iter().flatten().find(|e| e.len() != 96) {
+            anyhow::bail!(
+                "{:?}: expected embeddings of 96 values, found {}",
+                path,
+                e.len()
+            );
+        }
+        if !(template.threshold.is_finite() && template.threshold > 0.) {
+            anyhow::bail!("{:?}: threshold must be positive", path);
+        }
+        Ok(template)
+    }
+
+    /// Scales every embedding of the examples to unit length, so cosine distances are
+    /// quick to compute while scoring.
+    pub(crate) fn normalize(&mut self) {
+        self.examples
+            .iter_mut()
+            .flatten()
+            .for_each(|e| *e = normalized(e.iter()));
+    }
+
+    /// Writes the template as JSON. It is only loaded as a template from a path ending in
+    /// `.template`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), anyhow::Error> {
+        let path = path.as_ref();
+        let f = std::fs::File::create(path)
+            .map_err(|e| anyhow::anyhow!("creating {:?}: {}", path, e))?;
+        serde_json::to_writer(std::io::BufWriter::new(f), self)?;
+        Ok(())
+    }
+
+    /// The distance from each example to a window of embeddings ending as a phrase might.
+    pub fn distances(&self, window: &[Embedding]) -> Vec<f32> {
+        let window: Vec<f32> = window.iter().flat_map(|e| normalized(e.iter())).collect();
+        let mut rows = Rows::default();
+        self.examples
+            .iter()
+            .map(|example| {
+                let example: Vec<_> = example.iter().map(|e| normalized(e.iter())).collect();
+                dtw(&example, &window, &mut rows)
+            })
+            .collect()
+    }
+
+    /// Scores a window of embeddings flattened from [1, N, 96], once the examples have
+    /// been normalized.
+    pub(crate) fn score(&self, input: &[f32], scratch: &mut Scratch) -> f32 {
+        let Scratch { window, rows } = scratch;
+        window.clear();
+        window.extend_from_slice(input);
+        window.chunks_mut(96).for_each(normalize);
+        let distance = self
+            .examples
+            .iter()
+            .map(|example| dtw(example, window, rows))
+            .fold(f32::INFINITY, f32::min);
+        (1. - distance / (2. * self.threshold)).clamp(0., 1.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sequence of distinct unit embeddings, one for each of indices.
+    fn sequence(indices: impl IntoIterator<Item = usize>) -> Vec<Vec<f32>> {
+        indices
+            .into_iter()
+            .map(|i| {
+                let mut e = vec![0.; 96];
+                e[i] = 1.;
+                e
+            })
+            .collect()
+    }
+
+    /// Aligns example with the end of window, as the template does.
+    fn align(example: &[Vec<f32>], window: &[Vec<f32>]) -> f32 {
+        dtw(example, &window.concat(), &mut Rows::default())
+    }
+
+    #[test]
+    fn dtw_matches_identical_sequences() {
+        let example = sequence(0..8);
+        assert_eq!(align(&example, &example), 0.);
+
+        // The example can start anywhere, as long as it ends with the window.
+        let window = sequence((20..28).chain(0..8));
+        assert_eq!(align(&example, &window), 0.);
+    }
+
+    #[test]
+    fn dtw_matches_time_stretched_sequences() {
+        let example = sequence(0..8);
+        // Spoken twice as slowly as the example.
+        let slow = sequence((0..8).flat_map(|i| [i, i]));
+        assert_eq!(align(&example, &slow), 0.);
+        // Spoken twice as fast as the example.
+        assert_eq!(align(&slow, &example), 0.);
+    }
+
+    #[test]
+    fn dtw_rejects_unrelated_sequences() {
+        let example = sequence(0..8);
+        assert_eq!(align(&example, &sequence(20..36)), 1.);
+
+        // Matching the start of the example isn't enough, it must end with the window.
+        let distance = align(&example, &sequence((0..4).chain(20..24)));
+        assert!(distance > 0.4, "distance {}", distance);
+    }
+
+    #[test]
+    fn templates_have_their_own_extension() {
+        assert!(Template::is_template("models/phrase.template"));
+        assert!(!Template::is_template("models/phrase.json"));
+        assert!(!Template::is_template("models/phrase.onnx"));
+    }
+
+    #[test]
+    fn scoring_reuses_its_buffers() {
+        let examples: Vec<Vec<Embedding>> = (0..3)
+            .map(|i| {
+                sequence(i..i + 12)
+                    .into_iter()
+                    .map(|e| Embedding::from(<[f32; 96]>::try_from(e).unwrap()))
+                    .collect()
+            })
+            .collect();
+        let mut template = Template::new(&examples, 0.3);
+        template.normalize();
+
+        // Ends differently from every example.
+        let window = sequence((20..24).chain(0..11).chain([40])).concat();
+        let mut scratch = Scratch::default();
+        let score = template.score(&window, &mut scratch);
+        assert!(score > 0. && score < 1., "score {}", score);
+        let buffers = |s: &Scratch| [s.window.as_ptr(), s.rows.prev.as_ptr(), s.rows.cur.as_ptr()];
+        let before = buffers(&scratch);
+        assert_eq!(template.score(&window, &mut scratch), score);
+        assert_eq!(template.score(&window, &mut Scratch::default()), score);
+        // The rows swap, but neither is reallocated.
+        let mut after = buffers(&scratch);
+        after[1..].sort();
+        let mut expected = before;
+        expected[1..].sort();
+        assert_eq!(after, expected);
+    }
+}
@@ -0,0 +1,554 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sampler::SAMPLE_RATE;
+
+/// A recorded utterance, both as written to disk and as raw samples.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Utterance {
+    pub path: PathBuf,
+    pub samples: Vec<f32>,
+}
+
+/// The text recognized in an utterance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transcript {
+    pub path: PathBuf,
+    pub text: String,
+}
+
+/// Transcriber converts recorded utterances to text.
+pub trait Transcriber: Send {
+    fn transcribe(&self, utterance: &Utterance) -> Result<String, anyhow::Error>;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum TranscriberConfig {
+    /// Runs a command with the utterance path as the first argument, and reads the
+    /// transcript from its stdout.
+    Command { command: String },
+    /// POSTs the utterance to an OpenAI-compatible `/audio/transcriptions` endpoint.
+    OpenAI {
+        url: String,
+        #[serde(default)]
+        model: Option<String>,
+        /// Name of the environment variable holding the bearer token.
+        #[serde(default)]
+        api_key_env: Option<String>,
+        #[serde(default)]
+        language: Option<String>,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+    /// Streams the utterance to a Wyoming ASR server, such as wyoming-faster-whisper.
+    Wyoming {
+        address: String,
+        #[serde(default)]
+        language: Option<String>,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+}
+
+impl TranscriberConfig {
+    pub fn build(&self) -> Result<Box<dyn Transcriber>, anyhow::Error> {
+        Ok(match self {
+            TranscriberConfig::Command { command } => Box::new(CommandTranscriber::new(command)?),
+            TranscriberConfig::OpenAI {
+                url,
+                model,
+                api_key_env,
+                language,
+                timeout_ms,
+            } => {
+                let api_key = match api_key_env {
+                    Some(var) => Some(
+                        std::env::var(var)
+                            .map_err(|e| anyhow::anyhow!("reading api key from ${}: {}", var, e))?,
+                    ),
+                    None => None,
+                };
+                Box::new(OpenAITranscriber {
+                    url: url.clone(),
+                    model: model.clone().unwrap_or("whisper-1".to_string()),
+                    api_key,
+                    language: language.clone(),
+                    timeout: Duration::from_millis(timeout_ms.unwrap_or(30_000)),
+                })
+            }
+            TranscriberConfig::Wyoming {
+                address,
+                language,
+                timeout_ms,
+            } => Box::new(WyomingTranscriber {
+                address: address.clone(),
+                language: language.clone(),
+                timeout: Duration::from_millis(timeout_ms.unwrap_or(30_000)),
+            }),
+        })
+    }
+}
+
+/// CommandTranscriber runs a command and uses its trimmed stdout as the transcript.
+pub struct CommandTranscriber {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandTranscriber {
+    pub fn new(command: &str) -> Result<Self, anyhow::Error> {
+        let mut spl = shlex::Shlex::new(command);
+        let program = spl
+            .next()
+            .ok_or(anyhow::anyhow!("empty transcriber command"))?;
+        Ok(Self {
+            program,
+            args: spl.collect(),
+        })
+    }
+}
+
+impl Transcriber for CommandTranscriber {
+    fn transcribe(&self, utterance: &Utterance) -> Result<String, anyhow::Error> {
+        let out = Command::new(&self.program)
+            .arg(&utterance.path)
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()?;
+        if !out.status.success() {
+            anyhow::bail!("{} exited with {}", self.program, out.status);
+        }
+        Ok(String::from_utf8(out.stdout)?.trim().to_string())
+    }
+}
+
+/// OpenAITranscriber uploads utterances to an OpenAI-compatible transcription API.
+pub struct OpenAITranscriber {
+    pub url: String,
+    pub model: String,
+    pub api_key: Option<String>,
+    pub language: Option<String>,
+    pub timeout: Duration,
+}
+
+impl OpenAITranscriber {
+    fn multipart_body(&self, boundary: &str, path: &Path) -> Result<Vec<u8>, anyhow::Error> {
+        let mut body: Vec<u8> = Vec::new();
+        let mut field = |name: &str, value: &str| {
+            write!(
+                body,
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+        };
+        field("model", &self.model)?;
+        field("response_format", "json")?;
+        field("temperature", "0")?;
+        if let Some(language) = &self.language {
+            field("language", language)?;
+        }
+
+        let fname = path
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or("utterance.wav".to_string());
+        let content_type = match path.extension().and_then(|e| e.to_str()) {
+            Some("flac") => "audio/flac",
+            _ => "audio/wav",
+        };
+        write!(
+            body,
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+            boundary, fname, content_type
+        )?;
+        body.extend(std::fs::read(path)?);
+        write!(body, "\r\n--{}--\r\n", boundary)?;
+        Ok(body)
+    }
+}
+
+impl Transcriber for OpenAITranscriber {
+    fn transcribe(&self, utterance: &Utterance) -> Result<String, anyhow::Error> {
+        #[derive(Deserialize)]
+        struct Response {
+            text: String,
+        }
+
+        let boundary = format!(
+            "oww-{:x}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_nanos()
+        );
+        let body = self.multipart_body(&boundary, &utterance.path)?;
+
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(self.timeout))
+            .build()
+            .into();
+        let mut req = agent.post(&self.url).header(
+            "Content-Type",
+            format!("multipart/form-data; boundary={}", boundary),
+        );
+        if let Some(key) = &self.api_key {
+            req = req.header("Authorization", format!("Bearer {}", key));
+        }
+
+        let text = req.send(&body[..])?.body_mut().read_to_string()?;
+        Ok(serde_json::from_str::<Response>(&text)?
+            .text
+            .trim()
+            .to_string())
+    }
+}
+
+/// WyomingTranscriber streams utterances to a server speaking the Wyoming protocol.
+pub struct WyomingTranscriber {
+    pub address: String,
+    pub language: Option<String>,
+    pub timeout: Duration,
+}
+
+impl WyomingTranscriber {
+    fn write_event(
+        stream: &mut TcpStream,
+        kind: &str,
+        data: serde_json::Value,
+        payload: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        let data = serde_json::to_vec(&data)?;
+        let mut header = serde_json::json!({
+            "type": kind,
+            "data_length": data.len(),
+        });
+        if !payload.is_empty() {
+            header["payload_length"] = payload.len().into();
+        }
+
+        let mut buf = serde_json::to_vec(&header)?;
+        buf.push(b'\n');
+        buf.extend(data);
+        buf.extend(payload);
+        stream.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn read_event<R: BufRead>(
+        reader: &mut R,
+    ) -> Result<(String, serde_json::Value), anyhow::Error> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            anyhow::bail!("connection closed before transcript was received");
+        }
+        let header: serde_json::Value = serde_json::from_str(&line)?;
+        let kind = header["type"].as_str().unwrap_or_default().to_string();
+
+        let mut data = header.get("data").cloned().unwrap_or(serde_json::json!({}));
+        if let Some(len) = header["data_length"].as_u64() {
+            let mut buf = vec![0u8; len as usize];
+            reader.read_exact(&mut buf)?;
+            let extra: serde_json::Value = serde_json::from_slice(&buf)?;
+            if let (Some(data), Some(extra)) = (data.as_object_mut(), extra.as_object()) {
+                data.extend(extra.clone());
+            }
+        }
+        if let Some(len) = header["payload_length"].as_u64() {
+            std::io::copy(&mut reader.take(len), &mut std::io::sink())?;
+        }
+        Ok((kind, data))
+    }
+}
+
+impl Transcriber for WyomingTranscriber {
+    fn transcribe(&self, utterance: &Utterance) -> Result<String, anyhow::Error> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let format = serde_json::json!({"rate": SAMPLE_RATE, "width": 2, "channels": 1});
+        let mut transcribe = serde_json::json!({});
+        if let Some(language) = &self.language {
+            transcribe["language"] = language.clone().into();
+        }
+        Self::write_event(&mut stream, "transcribe", transcribe, &[])?;
+        Self::write_event(&mut stream, "audio-start", format.clone(), &[])?;
+
+        // Send the audio in one second chunks of 16-bit PCM.
+        for chunk in utterance.samples.chunks(SAMPLE_RATE) {
+            let pcm: Vec<u8> = chunk
+                .iter()
+                .flat_map(|s| {
+                    ((*s * (i16::MAX as f32))
+                        .max(i16::MIN as f32)
+                        .min(i16::MAX as f32) as i16)
+                        .to_le_bytes()
+                })
+                .collect();
+            Self::write_event(&mut stream, "audio-chunk", format.clone(), &pcm)?;
+        }
+        Self::write_event(&mut stream, "audio-stop", serde_json::json!({}), &[])?;
+
+        let mut reader = BufReader::new(stream);
+        loop {
+            let (kind, data) = Self::read_event(&mut reader)?;
+            if kind == "transcript" {
+                return Ok(data["text"].as_str().unwrap_or_default().trim().to_string());
+            }
+        }
+    }
+}
+
+/// Transcription runs a transcriber over utterances in the background, outputting transcripts.
+pub struct Transcription {
+    recv: Option<Receiver<Transcript>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Transcription {
+    pub fn start(
+        utterances: Receiver<Utterance>,
+        transcriber: Box<dyn Transcriber>,
+    ) -> Result<Self, anyhow::Error> {
+        let (send, recv) = sync_channel(4);
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let shutdown2 = shutdown.clone();
+        let thread = Some(thread::spawn(move || {
+            Transcription::mainloop(send, shutdown2, utterances, transcriber);
+        }));
+
+        let out = Self {
+            shutdown,
+            thread,
+            recv: Some(recv),
+        };
+
+        Ok(out)
+    }
+
+    pub fn take_receiver(&mut self) -> Option<Receiver<Transcript>> {
+        self.recv.take()
+    }
+
+    fn mainloop(
+        tx: SyncSender<Transcript>,
+        shutdown: Arc<AtomicBool>,
+        utterances: Receiver<Utterance>,
+        transcriber: Box<dyn Transcriber>,
+    ) {
+        loop {
+            if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+                return;
+            }
+            let utterance = match utterances.recv() {
+                Ok(u) => u,
+                Err(_e) => return,
+            };
+
+            let text = match transcriber.transcribe(&utterance) {
+                Ok(text) => text,
+                Err(e) => {
+                    println!("failed transcribing {:?}: {:?}", utterance.path, e);
+                    continue;
+                }
+            };
+            if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+                return;
+            }
+
+            let transcript = Transcript {
+                path: utterance.path,
+                text,
+            };
+            if let Err(e) = tx.send(transcript) {
+                println!("failed send, transcription thread shutting down! {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for Transcription {
+    fn drop(&mut self) {
+        self.shutdown
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        if let Some(hnd) = self.thread.take() {
+            hnd.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn utterance(name: &str, samples: Vec<f32>) -> Utterance {
+        let path = std::env::temp_dir().join(format!("oww-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, b"RIFF audio").unwrap();
+        Utterance { path, samples }
+    }
+
+    #[test]
+    fn command_passes_path_and_reads_stdout() {
+        let u = utterance("command.wav", vec![]);
+        let t = CommandTranscriber::new("echo heard").unwrap();
+        assert_eq!(
+            t.transcribe(&u).unwrap(),
+            format!("{} heard", u.path.display())
+        );
+        assert!(
+            CommandTranscriber::new("false")
+                .unwrap()
+                .transcribe(&u)
+                .is_err()
+        );
+        std::fs::remove_file(&u.path).ok();
+    }
+
+    /// Accepts one HTTP request, replying with body and returning the request.
+    fn http_stub(body: &'static str) -> (String, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/v1/audio/transcriptions",
+            listener.local_addr().unwrap()
+        );
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = Vec::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some((k, v)) = line.split_once(':')
+                    && k.eq_ignore_ascii_case("content-length")
+                {
+                    content_length = v.trim().parse().unwrap();
+                }
+                request.extend(line.as_bytes());
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut content = vec![0u8; content_length];
+            reader.read_exact(&mut content).unwrap();
+            request.extend(content);
+
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            request
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn openai_posts_multipart_and_parses_text() {
+        let (url, stub) = http_stub(r#"{"text": " turn on the lights "}"#);
+        let u = utterance("openai.flac", vec![]);
+        let t = OpenAITranscriber {
+            url,
+            model: "whisper-1".to_string(),
+            api_key: Some("secret".to_string()),
+            language: Some("en".to_string()),
+            timeout: Duration::from_secs(5),
+        };
+        assert_eq!(t.transcribe(&u).unwrap(), "turn on the lights");
+
+        let request = String::from_utf8(stub.join().unwrap()).unwrap();
+        let lower = request.to_lowercase();
+        assert!(request.starts_with("POST /v1/audio/transcriptions HTTP/1.1\r\n"));
+        assert!(lower.contains("authorization: bearer secret\r\n"));
+        assert!(lower.contains("content-type: multipart/form-data; boundary=oww-"));
+        assert!(request.contains("name=\"model\"\r\n\r\nwhisper-1\r\n"));
+        assert!(request.contains("name=\"language\"\r\n\r\nen\r\n"));
+        assert!(request.contains(&format!(
+            "name=\"file\"; filename=\"{}\"\r\nContent-Type: audio/flac\r\n\r\nRIFF audio\r\n",
+            u.path.file_name().unwrap().to_string_lossy()
+        )));
+        std::fs::remove_file(&u.path).ok();
+    }
+
+    #[test]
+    fn wyoming_streams_audio_and_reads_transcript() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let stub = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut events = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let header: serde_json::Value = serde_json::from_str(&line).unwrap();
+                let mut data = vec![0u8; header["data_length"].as_u64().unwrap() as usize];
+                reader.read_exact(&mut data).unwrap();
+                let mut payload =
+                    vec![0u8; header["payload_length"].as_u64().unwrap_or(0) as usize];
+                reader.read_exact(&mut payload).unwrap();
+
+                let kind = header["type"].as_str().unwrap().to_string();
+                let data: serde_json::Value = serde_json::from_slice(&data).unwrap();
+                events.push((kind.clone(), data, payload.len()));
+                if kind == "audio-stop" {
+                    break;
+                }
+            }
+            // Servers may send other events before the transcript.
+            WyomingTranscriber::write_event(&mut stream, "info", serde_json::json!({}), &[])
+                .unwrap();
+            WyomingTranscriber::write_event(
+                &mut stream,
+                "transcript",
+                serde_json::json!({"text": " what time is it "}),
+                &[],
+            )
+            .unwrap();
+            events
+        });
+
+        let u = utterance("wyoming.wav", vec![0.5; SAMPLE_RATE * 3 / 2]);
+        let t = WyomingTranscriber {
+            address,
+            language: Some("en".to_string()),
+            timeout: Duration::from_secs(5),
+        };
+        assert_eq!(t.transcribe(&u).unwrap(), "what time is it");
+
+        let events = stub.join().unwrap();
+        let kinds: Vec<_> = events.iter().map(|(k, _, _)| k.as_str()).collect();
+        assert_eq!(
+            kinds,
+            [
+                "transcribe",
+                "audio-start",
+                "audio-chunk",
+                "audio-chunk",
+                "audio-stop"
+            ]
+        );
+        assert_eq!(events[0].1["language"], "en");
+        assert_eq!(events[1].1["rate"], SAMPLE_RATE);
+        // One second and then half a second of 16-bit samples.
+        assert_eq!(events[2].2, SAMPLE_RATE * 2);
+        assert_eq!(events[3].2, SAMPLE_RATE);
+        std::fs::remove_file(&u.path).ok();
+    }
+}
@@ -0,0 +1,625 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, sync_channel};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use flacenc::component::BitRepr;
+use flacenc::error::Verify;
+use serde::{Deserialize, Serialize};
+
+use crate::sampler::SAMPLE_RATE;
+use crate::{RetentionConfig, UtteranceConfig, VAD, VadSegment};
+
+/// Encoding used when writing recorded utterances to disk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UtteranceFormat {
+    /// Uncompressed floating-point WAV.
+    #[default]
+    Wav,
+    /// Lossless FLAC at 16 bits per sample, typically around half the size of the WAV.
+    Flac,
+}
+
+impl UtteranceFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            UtteranceFormat::Wav => "wav",
+            UtteranceFormat::Flac => "flac",
+        }
+    }
+
+    /// Encodes mono samples at the sample rate of the pipeline and writes them to path.
+    pub fn write<P: AsRef<Path>>(&self, path: P, samples: &[f32]) -> Result<(), anyhow::Error> {
+        match self {
+            UtteranceFormat::Wav => {
+                wavers::write(path.as_ref(), samples, SAMPLE_RATE as i32, 1)?;
+            }
+            UtteranceFormat::Flac => {
+                let samples: Vec<i32> = samples
+                    .iter()
+                    .map(|s| {
+                        (*s * (i16::MAX as f32))
+                            .max(i16::MIN as f32)
+                            .min(i16::MAX as f32) as i32
+                    })
+                    .collect();
+
+                let config = flacenc::config::Encoder::default()
+                    .into_verified()
+                    .map_err(|(_, e)| anyhow::anyhow!("invalid flac config: {:?}", e))?;
+                let source = flacenc::source::MemSource::from_samples(&samples, 1, 16, SAMPLE_RATE);
+                let stream =
+                    flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+                        .map_err(|e| anyhow::anyhow!("flac encoding failed: {:?}", e))?;
+
+                let mut sink = flacenc::bitsink::ByteSink::new();
+                stream
+                    .write(&mut sink)
+                    .map_err(|e| anyhow::anyhow!("flac encoding failed: {:?}", e))?;
+                std::fs::write(path, sink.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Metadata describing a recorded utterance, written as a JSON sidecar next to the audio.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UtteranceMeta {
+    pub trigger_model: String,
+    pub peak_score: f32,
+    pub started_at: String,
+    pub ended_at: String,
+    pub sample_rate: usize,
+    pub num_samples: usize,
+    pub vad_segments: Vec<VadSegment>,
+}
+
+impl UtteranceMeta {
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), anyhow::Error> {
+        let f = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+}
+
+/// An utterance recorded after a wakeword, ready to be saved.
+#[derive(Debug, Clone)]
+pub struct RecordedUtterance {
+    pub samples: Vec<f32>,
+    pub trigger_model: String,
+    pub peak_score: f32,
+    pub started_at: chrono::DateTime<chrono::Local>,
+    pub ended_at: chrono::DateTime<chrono::Local>,
+}
+
+/// An utterance which has been written to disk.
+#[derive(Debug, Clone)]
+pub struct SavedUtterance {
+    pub path: PathBuf,
+    pub utterance: RecordedUtterance,
+}
+
+pub const DEFAULT_FILENAME: &str = "utterance_{timestamp}{millis}";
+
+/// File in the utterance directory listing the utterances written by the store, so
+/// retention only ever deletes those.
+const MANIFEST: &str = ".utterances";
+
+/// How often retention is enforced while no utterances are being saved, so old
+/// utterances are removed by age even while idle.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Checks that retention can safely be enforced with a config: utterances need a
+/// directory of their own.
+pub(crate) fn check_retention(config: &UtteranceConfig) -> Result<(), &'static str> {
+    if config.retention.is_enabled() && config.dir.is_none() {
+        return Err("requires utterance.dir, a directory used only for utterances");
+    }
+    Ok(())
+}
+
+/// UtteranceStore names utterances written to a directory, and prunes old utterances
+/// according to a retention policy.
+#[derive(Debug)]
+pub struct UtteranceStore {
+    dir: PathBuf,
+    template: String,
+    format: UtteranceFormat,
+    sidecar: bool,
+    retention: RetentionConfig,
+    seq: u64,
+}
+
+impl UtteranceStore {
+    /// Creates the utterance directory if needed. Fails if retention is configured
+    /// without a directory of its own, see [UtteranceStore::enforce_retention].
+    pub fn new(config: &UtteranceConfig) -> Result<Self, anyhow::Error> {
+        check_retention(config).map_err(|e| anyhow::anyhow!("utterance.retention {}", e))?;
+        let dir = match &config.dir {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::temp_dir(),
+        };
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            template: config
+                .filename
+                .clone()
+                .unwrap_or(DEFAULT_FILENAME.to_string()),
+            format: config.format,
+            sidecar: config.sidecar,
+            retention: config.retention.clone(),
+            seq: 0,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the path the next utterance should be written to. The path is guaranteed
+    /// not to exist at the time of the call.
+    pub fn next_path(&mut self, model: &str) -> PathBuf {
+        let now = chrono::Local::now();
+        let name = self
+            .template
+            .replace("{model}", model)
+            .replace("{seq}", &self.seq.to_string())
+            .replace("{timestamp}", &now.format("%Y%m%d%H%M%S").to_string())
+            .replace("{millis}", &now.format("%3f").to_string())
+            .replace("{unix_ms}", &now.timestamp_millis().to_string());
+        self.seq += 1;
+
+        let mut path = self
+            .dir
+            .join(format!("{}.{}", name, self.format.extension()));
+        let mut n = 1;
+        while path.exists() {
+            path = self
+                .dir
+                .join(format!("{}_{}.{}", name, n, self.format.extension()));
+            n += 1;
+        }
+        path
+    }
+
+    /// Writes an utterance to the next path, along with its sidecar if enabled, returning
+    /// the path.
+    pub fn save(&mut self, utterance: &RecordedUtterance) -> Result<PathBuf, anyhow::Error> {
+        let path = self.next_path(&utterance.trigger_model);
+        self.format.write(&path, &utterance.samples)?;
+
+        if self.sidecar {
+            let meta = UtteranceMeta {
+                trigger_model: utterance.trigger_model.clone(),
+                peak_score: utterance.peak_score,
+                started_at: utterance.started_at.to_rfc3339(),
+                ended_at: utterance.ended_at.to_rfc3339(),
+                sample_rate: SAMPLE_RATE,
+                num_samples: utterance.samples.len(),
+                vad_segments: VAD::segments(&utterance.samples)?,
+            };
+            meta.write(path.with_extension("json"))?;
+        }
+
+        if self.retention.is_enabled() {
+            use std::io::Write;
+            let mut manifest = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join(MANIFEST))?;
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                writeln!(manifest, "{}", name)?;
+            }
+        }
+        Ok(path)
+    }
+
+    /// Deletes the oldest utterances (and their sidecars) until the retention policy
+    /// is satisfied, returning the number of utterances removed.
+    ///
+    /// Only utterances saved by a store, which are listed in a manifest in the directory,
+    /// are considered, so other files in the directory are left alone.
+    pub fn enforce_retention(&self) -> Result<usize, anyhow::Error> {
+        if !self.retention.is_enabled() {
+            return Ok(0);
+        }
+        let manifest = self.dir.join(MANIFEST);
+        let names = match std::fs::read_to_string(&manifest) {
+            Ok(names) => names,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut utterances: Vec<(PathBuf, SystemTime, u64)> = Vec::new();
+        for name in names.lines().filter(|n| !n.is_empty()) {
+            // Names are written by the store, but guard against anything outside the
+            // directory all the same.
+            if Path::new(name).file_name() != Some(name.as_ref()) {
+                continue;
+            }
+            let path = self.dir.join(name);
+            let meta = match std::fs::metadata(&path) {
+                Ok(meta) if meta.is_file() => meta,
+                _ => continue,
+            };
+            let sidecar_len = std::fs::metadata(path.with_extension("json"))
+                .map(|m| m.len())
+                .unwrap_or(0);
+            utterances.push((path, meta.modified()?, meta.len() + sidecar_len));
+        }
+        // Newest first, so we can keep a prefix of the list.
+        utterances.sort_by_key(|u| std::cmp::Reverse(u.1));
+
+        let now = SystemTime::now();
+        let mut total_bytes = 0u64;
+        let mut removed = 0;
+        let mut kept = Vec::with_capacity(utterances.len());
+        for (i, (path, modified, len)) in utterances.into_iter().enumerate() {
+            total_bytes += len;
+            let too_many = self.retention.max_files.is_some_and(|max| i >= max);
+            let too_big = self
+                .retention
+                .max_bytes
+                .is_some_and(|max| total_bytes > max);
+            let too_old = self.retention.max_age_secs.is_some_and(|max| {
+                now.duration_since(modified).unwrap_or_default() > Duration::from_secs(max)
+            });
+
+            if too_many || too_big || too_old {
+                std::fs::remove_file(&path)?;
+                std::fs::remove_file(path.with_extension("json")).ok();
+                removed += 1;
+            } else {
+                kept.push(path);
+            }
+        }
+
+        // Rewrite the manifest oldest first, dropping removed and missing utterances.
+        let mut names = String::new();
+        for path in kept.iter().rev() {
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                names.push_str(name);
+                names.push('\n');
+            }
+        }
+        let tmp = self.dir.join(format!("{}.tmp", MANIFEST));
+        std::fs::write(&tmp, names)?;
+        std::fs::rename(&tmp, &manifest)?;
+        Ok(removed)
+    }
+}
+
+/// UtteranceWriter saves recorded utterances with an [UtteranceStore] on its own thread,
+/// so encoding them and running VAD over them doesn't hold up detection. The retention
+/// policy is enforced when it starts, after each save and every minute otherwise.
+pub struct UtteranceWriter {
+    recv: Option<Receiver<SavedUtterance>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl UtteranceWriter {
+    pub fn start(
+        store: UtteranceStore,
+        utterances: Receiver<RecordedUtterance>,
+    ) -> Result<Self, anyhow::Error> {
+        let (send, recv) = sync_channel(4);
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let shutdown2 = shutdown.clone();
+        let thread = Some(thread::spawn(move || {
+            UtteranceWriter::mainloop(send, shutdown2, utterances, store);
+        }));
+
+        let out = Self {
+            shutdown,
+            thread,
+            recv: Some(recv),
+        };
+
+        Ok(out)
+    }
+
+    pub fn take_receiver(&mut self) -> Option<Receiver<SavedUtterance>> {
+        self.recv.take()
+    }
+
+    fn mainloop(
+        tx: SyncSender<SavedUtterance>,
+        shutdown: Arc<AtomicBool>,
+        utterances: Receiver<RecordedUtterance>,
+        mut store: UtteranceStore,
+    ) {
+        let enforce_retention = |store: &UtteranceStore| {
+            if let Err(e) = store.enforce_retention() {
+                println!("failed enforcing utterance retention: {:?}", e);
+            }
+            std::time::Instant::now()
+        };
+        let mut last_retention = enforce_retention(&store);
+
+        loop {
+            if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+                return;
+            }
+            let utterance = match utterances.recv_timeout(Duration::from_secs(1)) {
+                Ok(u) => u,
+                Err(RecvTimeoutError::Timeout) => {
+                    if last_retention.elapsed() >= RETENTION_INTERVAL {
+                        last_retention = enforce_retention(&store);
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+
+            let saved = match store.save(&utterance) {
+                Ok(path) => Some(SavedUtterance { path, utterance }),
+                Err(e) => {
+                    println!("failed writing utterance: {:?}", e);
+                    None
+                }
+            };
+            last_retention = enforce_retention(&store);
+
+            if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+                return;
+            }
+            if let Some(saved) = saved
+                && let Err(e) = tx.send(saved)
+            {
+                println!(
+                    "failed send, utterance writer thread shutting down! {:?}",
+                    e
+                );
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for UtteranceWriter {
+    fn drop(&mut self) {
+        self.shutdown
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        if let Some(hnd) = self.thread.take() {
+            hnd.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for each test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("oww-utterance-{}-{}", std::process::id(), name));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn recorded(samples: Vec<f32>) -> RecordedUtterance {
+        let now = chrono::Local::now();
+        RecordedUtterance {
+            samples,
+            trigger_model: "wakeword".to_string(),
+            peak_score: 0.75,
+            started_at: now,
+            ended_at: now + chrono::Duration::seconds(1),
+        }
+    }
+
+    #[test]
+    fn writer_saves_flac_and_sidecar() {
+        let dir = test_dir("writer");
+        let config = UtteranceConfig {
+            dir: Some(dir.to_string_lossy().into_owned()),
+            format: UtteranceFormat::Flac,
+            sidecar: true,
+            ..UtteranceConfig::default()
+        };
+        let (send, recv) = sync_channel(1);
+        let mut writer =
+            UtteranceWriter::start(UtteranceStore::new(&config).unwrap(), recv).unwrap();
+        let saved = writer.take_receiver().unwrap();
+
+        // A tone at varying volume, clipped at the end, over several FLAC blocks.
+        let samples: Vec<f32> = (0..SAMPLE_RATE * 2)
+            .map(|i| (i as f32 * 0.05).sin() * (i as f32 / SAMPLE_RATE as f32))
+            .collect();
+        let utterance = recorded(samples.clone());
+        send.send(utterance.clone()).unwrap();
+        let saved = saved.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(saved.path.extension().unwrap(), "flac");
+
+        let mut reader = claxon::FlacReader::open(&saved.path).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(
+            (info.sample_rate, info.channels, info.bits_per_sample),
+            (SAMPLE_RATE as u32, 1, 16)
+        );
+        let decoded: Vec<i32> = reader.samples().map(|s| s.unwrap()).collect();
+        let expected: Vec<i32> = samples
+            .iter()
+            .map(|s| (s * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32) as i32)
+            .collect();
+        assert_eq!(decoded, expected);
+
+        let f = std::fs::File::open(saved.path.with_extension("json")).unwrap();
+        let meta: UtteranceMeta = serde_json::from_reader(f).unwrap();
+        assert_eq!(
+            meta,
+            UtteranceMeta {
+                trigger_model: "wakeword".to_string(),
+                peak_score: 0.75,
+                started_at: utterance.started_at.to_rfc3339(),
+                ended_at: utterance.ended_at.to_rfc3339(),
+                sample_rate: SAMPLE_RATE,
+                num_samples: samples.len(),
+                vad_segments: VAD::segments(&samples).unwrap(),
+            }
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    fn store(dir: &Path, filename: &str, retention: RetentionConfig) -> UtteranceStore {
+        UtteranceStore::new(&UtteranceConfig {
+            dir: Some(dir.to_string_lossy().into_owned()),
+            filename: Some(filename.to_string()),
+            retention,
+            ..UtteranceConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn name(path: &Path) -> &str {
+        path.file_name().unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn filenames_expand_templates() {
+        let dir = test_dir("template");
+        let mut store = store(
+            &dir,
+            "{model}-{seq}-{timestamp}-{millis}-{unix_ms}-{unknown}",
+            RetentionConfig::default(),
+        );
+        let pattern =
+            regex::Regex::new(r"^wakeword-(\d)-\d{14}-\d{3}-\d{13}-\{unknown\}\.wav$").unwrap();
+        for seq in 0..2 {
+            let path = store.next_path("wakeword");
+            assert_eq!(path.parent(), Some(dir.as_path()));
+            let captures = pattern.captures(name(&path)).unwrap();
+            assert_eq!(captures[1], seq.to_string());
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn filenames_never_collide() {
+        let dir = test_dir("collide");
+        let mut first = store(&dir, "u_{seq}", RetentionConfig::default());
+        assert_eq!(
+            name(&first.save(&recorded(vec![0.; 16])).unwrap()),
+            "u_0.wav"
+        );
+
+        // A new store starts counting again, but doesn't overwrite what the last wrote.
+        let mut second = store(&dir, "u_{seq}", RetentionConfig::default());
+        let saved: Vec<_> = (0..2)
+            .map(|_| second.save(&recorded(vec![0.; 16])).unwrap())
+            .collect();
+        assert_eq!(name(&saved[0]), "u_0_1.wav");
+        assert_eq!(name(&saved[1]), "u_1.wav");
+        assert_eq!(
+            name(&first.save(&recorded(vec![0.; 16])).unwrap()),
+            "u_1_1.wav"
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Saves utterances of the given lengths, a minute apart with the last saved now,
+    /// returning their paths oldest first.
+    fn save_aged(store: &mut UtteranceStore, lengths: &[usize]) -> Vec<PathBuf> {
+        let now = SystemTime::now();
+        lengths
+            .iter()
+            .enumerate()
+            .map(|(i, len)| {
+                let path = store.save(&recorded(vec![0.; *len])).unwrap();
+                let age = Duration::from_secs(60 * (lengths.len() - 1 - i) as u64);
+                std::fs::File::options()
+                    .write(true)
+                    .open(&path)
+                    .unwrap()
+                    .set_modified(now - age)
+                    .unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn kept(paths: &[PathBuf]) -> Vec<bool> {
+        paths.iter().map(|p| p.exists()).collect()
+    }
+
+    #[test]
+    fn retention_limits_number_of_files() {
+        let dir = test_dir("max-files");
+        let retention = RetentionConfig {
+            max_files: Some(2),
+            ..RetentionConfig::default()
+        };
+        let mut store = store(&dir, "u_{seq}", retention);
+        let paths = save_aged(&mut store, &[16, 16, 16]);
+        // Files the store didn't write are left alone.
+        let other = dir.join("other.wav");
+        std::fs::write(&other, b"other").unwrap();
+
+        assert_eq!(store.enforce_retention().unwrap(), 1);
+        assert_eq!(kept(&paths), [false, true, true]);
+        assert!(other.exists());
+        let manifest = std::fs::read_to_string(dir.join(MANIFEST)).unwrap();
+        assert_eq!(manifest, "u_1.wav\nu_2.wav\n");
+        assert_eq!(store.enforce_retention().unwrap(), 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn retention_limits_total_size() {
+        let dir = test_dir("max-bytes");
+        let retention = RetentionConfig {
+            max_bytes: Some(u64::MAX),
+            ..RetentionConfig::default()
+        };
+        let mut store = store(&dir, "u_{seq}", retention);
+        let paths = save_aged(&mut store, &[1000, 100, 1000, 100]);
+        let len = |i: usize| std::fs::metadata(&paths[i]).unwrap().len();
+
+        // The newest utterances are kept while they fit.
+        store.retention.max_bytes = Some(len(3) + len(2) + len(1));
+
+        assert_eq!(store.enforce_retention().unwrap(), 1);
+        assert_eq!(kept(&paths), [false, true, true, true]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn retention_limits_age() {
+        let dir = test_dir("max-age");
+        let retention = RetentionConfig {
+            max_age_secs: Some(90),
+            ..RetentionConfig::default()
+        };
+        let mut store = store(&dir, "u_{seq}", retention);
+        let paths = save_aged(&mut store, &[16, 16, 16]);
+
+        assert_eq!(store.enforce_retention().unwrap(), 1);
+        assert_eq!(kept(&paths), [false, true, true]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn retention_needs_a_directory() {
+        let mut config = UtteranceConfig {
+            filename: Some("{seq}".to_string()),
+            retention: RetentionConfig {
+                max_files: Some(1),
+                ..RetentionConfig::default()
+            },
+            ..UtteranceConfig::default()
+        };
+        assert!(check_retention(&config).is_err());
+        // Filenames no longer need to start with fixed text, as the manifest lists them.
+        config.dir = Some("utterances".to_string());
+        assert!(check_retention(&config).is_ok());
+    }
+}
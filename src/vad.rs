@@ -4,12 +4,16 @@ use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
 use std::thread;
 
 use earshot::{VoiceActivityDetector, VoiceActivityProfile};
+use serde::{Deserialize, Serialize};
 
 use crate::Chunk;
 
 /// VAD collects chunks of samples and computes a probability that voice is present.
+///
+/// Results are output alongside the id of the chunk they were computed from, so they
+/// can be placed in the audio stream.
 pub struct VAD {
-    recv: Option<Receiver<bool>>,
+    recv: Option<Receiver<(u64, bool)>>,
     shutdown: Arc<AtomicBool>,
     thread: Option<thread::JoinHandle<()>>,
 }
@@ -54,12 +58,12 @@ impl VAD {
         Ok(out)
     }
 
-    pub fn take_receiver(&mut self) -> Option<Receiver<bool>> {
+    pub fn take_receiver(&mut self) -> Option<Receiver<(u64, bool)>> {
         self.recv.take()
     }
 
     fn mainloop(
-        tx: SyncSender<bool>,
+        tx: SyncSender<(u64, bool)>,
         shutdown: Arc<AtomicBool>,
         samples: Receiver<Chunk<480>>,
         mut vad_model: VoiceActivityDetector,
@@ -90,11 +94,11 @@ impl VAD {
                 return;
             }
 
-            if let Err(e) = tx.try_send(out) {
-                if matches!(e, TrySendError::Disconnected(_)) {
-                    println!("failed send, VAD thread shutting down! {:?}", e);
-                    return;
-                }
+            if let Err(e) = tx.try_send((new_samples.id, out))
+                && matches!(e, TrySendError::Disconnected(_))
+            {
+                println!("failed send, VAD thread shutting down! {:?}", e);
+                return;
             }
         }
     }
@@ -109,3 +113,119 @@ impl Drop for VAD {
         }
     }
 }
+
+/// A span of detected voice activity, relative to the start of some recording.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VadSegment {
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+impl VAD {
+    /// Runs voice activity detection over an entire recording, returning the voiced segments.
+    pub fn segments(samples: &[f32]) -> Result<Vec<VadSegment>, anyhow::Error> {
+        let mut vad_model = VoiceActivityDetector::new(VoiceActivityProfile::VERY_AGGRESSIVE);
+        let mut tensor_data: Vec<i16> = Vec::with_capacity(480);
+        let mut out: Vec<VadSegment> = Vec::new();
+        let mut active: Option<u64> = None;
+
+        // 480 samples is 30ms of audio at 16khz.
+        for (i, frame) in samples.chunks_exact(480).enumerate() {
+            tensor_data.clear();
+            tensor_data.extend(frame.iter().map(|s| {
+                (*s * (i16::MAX as f32))
+                    .max(i16::MIN as f32)
+                    .min(i16::MAX as f32) as i16
+            }));
+
+            let at_ms = i as u64 * 30;
+            match (vad_model.predict_16khz(&tensor_data)?, active) {
+                (true, None) => active = Some(at_ms),
+                (false, Some(start_ms)) => {
+                    out.push(VadSegment {
+                        start_ms,
+                        end_ms: at_ms,
+                    });
+                    active = None;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(start_ms) = active {
+            out.push(VadSegment {
+                start_ms,
+                end_ms: (samples.len() / 480) as u64 * 30,
+            });
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A vowel-like sound: harmonics of a 150Hz pitch, shaped by two formants.
+    fn voice(len: usize) -> Vec<f32> {
+        let formant =
+            |f: f32, centre: f32, width: f32| (-(f - centre).powi(2) / (2. * width * width)).exp();
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / 16_000.;
+                (1..30)
+                    .map(|h| {
+                        let f = 150. * h as f32;
+                        let gain = formant(f, 700., 200.) + 0.6 * formant(f, 1200., 250.) + 0.1;
+                        gain * (2. * std::f32::consts::PI * f * t).sin() / h as f32
+                    })
+                    .sum::<f32>()
+                    * 0.2
+            })
+            .collect()
+    }
+
+    #[test]
+    fn results_follow_chunk_ids() {
+        let (send, recv) = sync_channel(1);
+        let mut vad = VAD::start(recv).unwrap();
+        let results = vad.take_receiver().unwrap();
+
+        let voiced = voice(480 * 10);
+        let mut out = Vec::new();
+        for id in 0..20u64 {
+            let mut chunk = Chunk {
+                id: 100 + id,
+                samples: [0.; 480],
+            };
+            if (5..15).contains(&id) {
+                let at = (id as usize - 5) * 480;
+                chunk.samples.copy_from_slice(&voiced[at..at + 480]);
+            }
+            send.send(chunk).unwrap();
+            // Wait for each result, as results are dropped while the last is unread.
+            out.push(results.recv().unwrap());
+        }
+        // Closes the stream, so the VAD thread exits.
+        drop(send);
+
+        let ids: Vec<u64> = out.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, (100..120).collect::<Vec<_>>());
+        assert!(out[..5].iter().all(|(_, voice)| !voice));
+        assert!(out[6..15].iter().all(|(_, voice)| *voice));
+        assert!(!out[19].1);
+    }
+
+    #[test]
+    fn segments_span_voice() {
+        let mut samples = vec![0.; 16_000 * 3 / 10];
+        samples.extend(voice(16_000 * 6 / 10));
+        samples.extend(vec![0.; 16_000 * 6 / 10]);
+
+        let segments = VAD::segments(&samples).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert!((270..=330).contains(&segments[0].start_ms));
+        assert!((900..=1200).contains(&segments[0].end_ms));
+        assert!(VAD::segments(&vec![0.; 16_000]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Embedding;
+
+/// Number of gradient descent steps taken while training.
+const ITERATIONS: usize = 1000;
+/// Inverse regularization strength, as sklearn's `C`. openWakeWord regularizes its
+/// verifiers strongly too, as they are trained on only a handful of clips.
+const C: f32 = 0.001;
+
+/// Verifier is a logistic regression over a window of embeddings, like openWakeWord's
+/// custom verifier models. Trained on clips of the people who use a model, it rejects
+/// activations caused by other voices and sounds. See [crate::VerifierConfig].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Verifier {
+    /// The number of embeddings the verifier takes, the same as the model it verifies.
+    pub window: usize,
+    /// One weight for each value in the window of embeddings, in order.
+    pub weights: Vec<f32>,
+    pub bias: f32,
+}
+
+/// Flattens each window of embeddings into the features a [Verifier] takes.
+pub fn windows(embeddings: &[Embedding], window: usize) -> impl Iterator<Item = Vec<f32>> + '_ {
+    embeddings
+        .windows(window)
+        .map(|w| w.iter().flat_map(|e| e.iter()).copied().collect())
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1. / (1. + (-x).exp())
+}
+
+impl Verifier {
+    /// Reads a verifier saved as JSON.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let f =
+            std::fs::File::open(path).map_err(|e| anyhow::anyhow!("opening {:?}: {}", path, e))?;
+        let verifier: Verifier = serde_json::from_reader(std::io::BufReader::new(f))
+            .map_err(|e| anyhow::anyhow!("reading {:?}: {}", path, e))?;
+        if verifier.weights.len() != verifier.window * 96 {
+            anyhow::bail!(
+                "{:?}: expected {} weights for a window of {} embeddings, found {}",
+                path,
+                verifier.window * 96,
+                verifier.window,
+                verifier.weights.len()
+            );
+        }
+        Ok(verifier)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), anyhow::Error> {
+        let path = path.as_ref();
+        let f = std::fs::File::create(path)
+            .map_err(|e| anyhow::anyhow!("creating {:?}: {}", path, e))?;
+        serde_json::to_writer(std::io::BufWriter::new(f), self)?;
+        Ok(())
+    }
+
+    /// Trains a verifier on windows of embeddings flattened by [windows]. Both classes
+    /// carry the same total weight however many examples each has, as there are usually
+    /// far more negative windows than positive ones.
+    pub fn train(
+        positive: &[Vec<f32>],
+        negative: &[Vec<f32>],
+        window: usize,
+    ) -> Result<Self, anyhow::Error> {
+        if positive.is_empty() || negative.is_empty() {
+            anyhow::bail!("both positive and negative examples are needed");
+        }
+        let dims = window * 96;
+        if let Some(x) = positive.iter().chain(negative).find(|x| x.len() != dims) {
+            anyhow::bail!("expected {} features, found {}", dims, x.len());
+        }
+
+        let n = (positive.len() + negative.len()) as f32;
+        let examples: Vec<(&Vec<f32>, f32, f32)> = positive
+            .iter()
+            .map(|x| (x, 1., n / (2. * positive.len() as f32)))
+            .chain(
+                negative
+                    .iter()
+                    .map(|x| (x, 0., n / (2. * negative.len() as f32))),
+            )
+            .collect();
+
+        // Standardize the features while training, so one step size suits them all.
+        let mut mean = vec![0f32; dims];
+        for (x, _, _) in examples.iter() {
+            mean.iter_mut().zip(x.iter()).for_each(|(m, v)| *m += v / n);
+        }
+        let mut std = vec![0f32; dims];
+        for (x, _, _) in examples.iter() {
+            std.iter_mut()
+                .zip(x.iter().zip(mean.iter()))
+                .for_each(|(s, (v, m))| *s += (v - m) * (v - m) / n);
+        }
+        std.iter_mut().for_each(|s| *s = s.sqrt().max(1e-6));
+        let scaled: Vec<Vec<f32>> = examples
+            .iter()
+            .map(|(x, _, _)| {
+                x.iter()
+                    .zip(mean.iter().zip(std.iter()))
+                    .map(|(v, (m, s))| (v - m) / s)
+                    .collect()
+            })
+            .collect();
+
+        // Minimizes the weighted mean log loss plus an L2 penalty, which matches sklearn's
+        // objective with C scaled by the number of examples. The step size is the inverse
+        // of a bound on the curvature, so plain gradient descent converges.
+        let lambda = 1. / (C * n);
+        let curvature = examples
+            .iter()
+            .zip(scaled.iter())
+            .map(|((_, _, weight), x)| {
+                0.25 * weight * (1. + x.iter().map(|v| v * v).sum::<f32>()) / n
+            })
+            .sum::<f32>()
+            + lambda;
+        let rate = 1. / curvature;
+
+        let mut weights = vec![0f32; dims];
+        let mut bias = 0f32;
+        let mut gradient = vec![0f32; dims];
+        for _ in 0..ITERATIONS {
+            gradient
+                .iter_mut()
+                .zip(weights.iter())
+                .for_each(|(g, w)| *g = lambda * w);
+            let mut bias_gradient = 0.;
+            for ((_, label, weight), x) in examples.iter().zip(scaled.iter()) {
+                let z = bias
+                    + x.iter()
+                        .zip(weights.iter())
+                        .map(|(v, w)| v * w)
+                        .sum::<f32>();
+                let error = weight * (sigmoid(z) - label) / n;
+                gradient
+                    .iter_mut()
+                    .zip(x.iter())
+                    .for_each(|(g, v)| *g += error * v);
+                bias_gradient += error;
+            }
+            weights
+                .iter_mut()
+                .zip(gradient.iter())
+                .for_each(|(w, g)| *w -= rate * g);
+            bias -= rate * bias_gradient;
+        }
+
+        // Fold the standardization into the weights, so it isn't needed to run.
+        for ((w, m), s) in weights.iter_mut().zip(mean.iter()).zip(std.iter()) {
+            *w /= s;
+            bias -= *w * m;
+        }
+
+        Ok(Self {
+            window,
+            weights,
+            bias,
+        })
+    }
+
+    /// The probability that a window of embeddings, flattened as by [windows], is of
+    /// the class the verifier was trained on.
+    pub fn probability(&self, features: &[f32]) -> f32 {
+        sigmoid(
+            self.bias
+                + features
+                    .iter()
+                    .zip(self.weights.iter())
+                    .map(|(v, w)| v * w)
+                    .sum::<f32>(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A window of one embedding, leaning towards label on the first few dimensions and
+    /// varying with i elsewhere.
+    fn example(label: bool, i: usize) -> Vec<f32> {
+        (0..96)
+            .map(|d| {
+                let noise = ((i * 37 + d * 11) % 17) as f32 / 17. - 0.5;
+                match (d < 8, label) {
+                    (true, true) => 1. + noise,
+                    (true, false) => -1. + noise,
+                    (false, _) => 2. * noise,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn separates_separable_examples() {
+        let positive: Vec<_> = (0..6).map(|i| example(true, i)).collect();
+        let negative: Vec<_> = (0..30).map(|i| example(false, i)).collect();
+        let verifier = Verifier::train(&positive, &negative, 1).unwrap();
+        assert_eq!(verifier.weights.len(), 96);
+
+        // Including examples it wasn't trained on.
+        for i in 0..60 {
+            assert!(
+                verifier.probability(&example(true, i)) > 0.5,
+                "positive {}",
+                i
+            );
+            assert!(
+                verifier.probability(&example(false, i)) < 0.5,
+                "negative {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_training_data() {
+        let positive = vec![example(true, 0)];
+        let negative = vec![example(false, 0)];
+        assert!(Verifier::train(&positive, &[], 1).is_err());
+        assert!(Verifier::train(&[], &negative, 1).is_err());
+        assert!(Verifier::train(&positive, &negative, 2).is_err());
+    }
+}